@@ -27,6 +27,11 @@ then
       parameter="$parameter --private_key $PRIVATE_KEY"
 fi
 
+if [ ! -z "$TRANSPORT" ]
+then
+      parameter="$parameter --transport $TRANSPORT"
+fi
+
 if [ ! -z "$AUTHORIZATION" ]
 then
       parameter="$parameter --private_key $AUTHORIZATION"
@@ -1,9 +1,35 @@
 #[macro_use]
 extern crate log;
 
-use std::str::FromStr;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use ss::{client::Client, server::Server};
+use ss::{
+    client::{upstream::ServerSpec, Client},
+    codec::proxy_protocol::ProxyProtocolVersion,
+    pool::retry::{CircuitBreaker, RetryPolicy},
+    server::{
+        acme::{AcmeChallenge, AcmeConfig},
+        decoy::Decoy,
+        forwarded::IpRange,
+        route::Route,
+        Server,
+    },
+    transport::{
+        tls::{
+            parse_spki_pin, parse_tls_version, spki_sha256, ClientTlsOptions, ServerTlsOptions,
+            TlsPreset,
+        },
+        ServerEndpoint, TransportType,
+    },
+    util::{
+        generate_self_signed, generate_token, load_certs, load_key_passphrase, load_private_key,
+    },
+};
 use structopt::StructOpt;
 
 // any error type implementing Display is acceptable.
@@ -26,8 +52,29 @@ enum Mode {
     Client,
 }
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// write a self signed certificate and its key for a test server
+    GenCert {
+        /// dns name or ip address the certificate is valid for, repeatable
+        #[structopt(long = "san", required = true)]
+        sans: Vec<String>,
+        #[structopt(long = "cert_out", default_value = "fullchain.pem")]
+        cert_out: PathBuf,
+        #[structopt(long = "key_out", default_value = "private.pem")]
+        key_out: PathBuf,
+        /// overwrite existing files
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    /// print a random authorization secret
+    GenToken,
+}
+
 #[derive(Debug, StructOpt)]
 struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
     #[structopt(short = "l", long = "listen_addr", default_value = "127.0.0.1:8080")]
     listen_addr: String,
     #[structopt(short = "f", long = "fullchain", default_value = "fullchain.pem")]
@@ -40,22 +87,406 @@ struct Opt {
     mode: Mode,
     #[structopt(short = "t", long = "authorization", default_value = "")]
     authorization: String,
+    /// websocket or quic, a quic server keeps accepting websocket on tcp
+    #[structopt(long = "transport", default_value = "websocket")]
+    transport: TransportType,
+    /// url path of the websocket on the server, e.g. /ws/tunnel behind a shared reverse proxy
+    #[structopt(long = "ws_path", default_value = "/")]
+    ws_path: String,
+    /// Host header sent to the server, defaults to proxy_addr
+    #[structopt(long = "host_header")]
+    host_header: Option<String>,
+    /// tls server name, defaults to the host of proxy_addr
+    #[structopt(long = "sni")]
+    sni: Option<String>,
+    /// host:port actually connected to, defaults to proxy_addr
+    #[structopt(long = "connect_addr")]
+    connect_addr: Option<String>,
+    /// Sec-WebSocket-Protocol offered to the server
+    #[structopt(long = "ws_protocol")]
+    ws_protocol: Option<String>,
+    /// another server for the client, repeatable, e.g. b.example.com,path=/ws,match=example.org
+    /// sends sessions to example.org and its subdomains there, without match sessions take turns
+    /// with proxy_addr, also host, sni, connect_addr, protocol and authorization can be set
+    #[structopt(long = "server")]
+    servers: Vec<ServerSpec>,
+    /// server side websocket path, repeatable, e.g. /ws/tunnel,group=team,protocol=ss,authorization=token
+    /// everything but the path is optional, only / is accepted when none is given
+    #[structopt(long = "route")]
+    routes: Vec<Route>,
+    /// pem bundle of extra certificate authorities the client trusts, repeatable
+    #[structopt(long = "ca_cert")]
+    ca_certs: Vec<PathBuf>,
+    /// base64 sha256 of the server SubjectPublicKeyInfo (`sha256//` prefix allowed), repeatable
+    #[structopt(long = "pin_sha256")]
+    spki_pins: Vec<String>,
+    /// do not verify the server certificate at all, only for local integration tests
+    #[structopt(long = "danger_accept_invalid_certs")]
+    danger_accept_invalid_certs: bool,
+    /// pem bundle of the ca client certificates have to be signed by, enables mutual tls on the server
+    #[structopt(long = "client_ca")]
+    client_ca_path: Option<PathBuf>,
+    /// certificate chain the client presents to servers requiring client certificates
+    #[structopt(long = "client_cert")]
+    client_cert_path: Option<PathBuf>,
+    /// private key of client_cert
+    #[structopt(long = "client_key")]
+    client_key_path: Option<PathBuf>,
+    /// directory with one sub directory per domain holding fullchain.pem and privkey.pem (certbot's live dir),
+    /// picked by sni and reloaded on change or SIGHUP, fullchain/private_key serve everything else
+    #[structopt(long = "cert_dir")]
+    cert_dir: Option<String>,
+    /// file holding the passphrase of encrypted pkcs#8 keys, SS_KEY_PASSPHRASE is used otherwise
+    #[structopt(long = "key_passphrase_file")]
+    key_passphrase_file: Option<PathBuf>,
+    /// tls version the server accepts on tcp, 1.2 or 1.3, repeatable, both by default
+    #[structopt(long = "tls_version")]
+    tls_versions: Vec<String>,
+    /// cipher suite the server accepts, e.g. TLS13_AES_128_GCM_SHA256, repeatable, in order of preference
+    #[structopt(long = "tls_cipher")]
+    tls_ciphers: Vec<String>,
+    /// pick the cipher suite by the order of tls_cipher instead of the client's
+    #[structopt(long = "tls_prefer_server_ciphers")]
+    tls_prefer_server_ciphers: bool,
+    /// alpn the server offers on tcp, repeatable, h2 and http/1.1 by default
+    #[structopt(long = "tls_alpn")]
+    tls_alpn: Vec<String>,
+    /// resume tls sessions with stateless tickets
+    #[structopt(long = "tls_session_tickets")]
+    tls_session_tickets: bool,
+    /// tls sessions kept for stateful resumption, 0 turns it off
+    #[structopt(long = "tls_session_cache", default_value = "256")]
+    tls_session_cache: usize,
+    /// make the client's ClientHello resemble chrome, firefox or safari,
+    /// the client_alpn/client_cipher/client_no_* flags below still apply on top
+    #[structopt(long = "tls_preset")]
+    tls_preset: Option<TlsPreset>,
+    /// alpn the client offers on tcp, repeatable, only what the transport speaks by default
+    #[structopt(long = "client_alpn")]
+    client_alpn: Vec<String>,
+    /// cipher suite the client offers, repeatable, in the order given
+    #[structopt(long = "client_cipher")]
+    client_ciphers: Vec<String>,
+    /// do not resume tls sessions on reconnect
+    #[structopt(long = "client_no_session_resumption")]
+    client_no_session_resumption: bool,
+    /// leave the server name out of the ClientHello, the certificate is still checked against it
+    #[structopt(long = "client_no_sni")]
+    client_no_sni: bool,
+    /// seconds a pooled websocket may stay unused before the client closes it, 0 keeps it forever
+    #[structopt(long = "pool_idle_timeout", default_value = "50")]
+    pool_idle_timeout: u64,
+    /// seconds after which a pooled websocket is not reused anymore, 0 for no limit
+    #[structopt(long = "pool_max_lifetime", default_value = "0")]
+    pool_max_lifetime: u64,
+    /// websockets the client opens ahead of time and keeps idle per server, at most 10
+    #[structopt(long = "pool_min_idle", default_value = "0")]
+    pool_min_idle: usize,
+    /// websockets the client opens at most, further sessions wait for one, 0 for no limit
+    #[structopt(long = "pool_max_size", default_value = "0")]
+    pool_max_size: usize,
+    /// seconds a session waits for a websocket when pool_max_size are in use
+    #[structopt(long = "pool_acquire_timeout", default_value = "30")]
+    pool_acquire_timeout: u64,
+    /// times a failed connection to the server is tried again, with a growing random delay
+    #[structopt(long = "connect_retries", default_value = "3")]
+    connect_retries: u32,
+    /// milliseconds before the first retry, doubled for every further one
+    #[structopt(long = "connect_backoff_ms", default_value = "200")]
+    connect_backoff_ms: u64,
+    /// upper bound of the retry delay in milliseconds
+    #[structopt(long = "connect_backoff_max_ms", default_value = "5000")]
+    connect_backoff_max_ms: u64,
+    /// failed connections in a row after which sessions fail fast for a while, 0 never
+    #[structopt(long = "breaker_threshold", default_value = "5")]
+    breaker_threshold: u32,
+    /// seconds sessions fail fast before one probes the server again
+    #[structopt(long = "breaker_open_secs", default_value = "10")]
+    breaker_open_secs: u64,
+    /// seconds between logs of websocket pool statistics, 0 never
+    #[structopt(long = "pool_stats_interval", default_value = "0")]
+    pool_stats_interval: u64,
+    /// serve plain ws behind a reverse proxy terminating tls, no certificate is needed
+    #[structopt(long = "plaintext")]
+    plaintext: bool,
+    /// address or network like 10.0.0.0/8 whose X-Forwarded-For and X-Real-IP name the client, repeatable
+    #[structopt(long = "trusted_proxy")]
+    trusted_proxies: Vec<IpRange>,
+    /// every tcp connection starts with a PROXY protocol v1 or v2 header, e.g. from haproxy or an aws nlb
+    #[structopt(long = "accept_proxy_protocol")]
+    accept_proxy_protocol: bool,
+    /// server sends a PROXY protocol header of this version (v1 or v2) to every destination
+    #[structopt(long = "send_proxy_protocol")]
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// answer requests which are not tunnels like a website, so probes do not stand out:
+    /// http://host:port reverse proxies to a local web server, a directory serves its files,
+    /// builtin serves a placeholder page
+    #[structopt(long = "decoy")]
+    decoy: Option<Decoy>,
+    /// acme directory url, enables obtaining and renewing the certificate of acme_domain by acme
+    #[structopt(long = "acme_directory")]
+    acme_directory: Option<String>,
+    /// domain the acme certificate is for, repeatable
+    #[structopt(long = "acme_domain")]
+    acme_domains: Vec<String>,
+    /// acme account contact, e.g. mailto:admin@example.com, repeatable
+    #[structopt(long = "acme_contact")]
+    acme_contacts: Vec<String>,
+    /// directory keeping the acme account key and certificates
+    #[structopt(long = "acme_cache", default_value = "acme")]
+    acme_cache: PathBuf,
+    /// answer http-01 on this address (e.g. 0.0.0.0:80) instead of tls-alpn-01 on listen_addr
+    #[structopt(long = "acme_http_addr")]
+    acme_http_addr: Option<String>,
+    /// pem bundle of extra certificate authorities trusted for the acme server, e.g. pebble's
+    #[structopt(long = "acme_ca_cert")]
+    acme_ca_certs: Vec<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let opt = Opt::from_args();
+    match opt.command {
+        Some(Command::GenCert {
+            sans,
+            cert_out,
+            key_out,
+            force,
+        }) => return gen_cert(&sans, cert_out, key_out, force),
+        Some(Command::GenToken) => {
+            println!("{}", generate_token());
+            return Ok(());
+        }
+        None => {}
+    }
+    let key_passphrase = load_key_passphrase(opt.key_passphrase_file.clone())?;
     match opt.mode {
+        Mode::Server if opt.plaintext => {
+            info!("plaintext server listen on {}", opt.listen_addr);
+            if opt.transport == TransportType::Quic || opt.acme_directory.is_some() {
+                return Err("quic and acme need tls, which plaintext leaves to the proxy".into());
+            }
+            let mut server = Server::plaintext(
+                opt.listen_addr,
+                opt.authorization,
+                opt.routes,
+                opt.trusted_proxies,
+                opt.decoy,
+            )?;
+            server.set_proxy_protocol(opt.accept_proxy_protocol, opt.send_proxy_protocol);
+            server.run().await
+        }
         Mode::Server => {
             info!("server listen on {}", opt.listen_addr);
-            let server = Server::new(opt.listen_addr, opt.fullchain_path, opt.private_key_path, opt.authorization)?;
+            let mut tls_options = ServerTlsOptions {
+                cipher_suites: opt.tls_ciphers,
+                prefer_server_ciphers: opt.tls_prefer_server_ciphers,
+                session_tickets: opt.tls_session_tickets,
+                session_cache_size: opt.tls_session_cache,
+                ..Default::default()
+            };
+            for version in &opt.tls_versions {
+                tls_options.versions.push(parse_tls_version(version)?);
+            }
+            if !opt.tls_alpn.is_empty() {
+                tls_options.alpn = opt.tls_alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+            }
+            if let Some(path) = opt.client_ca_path {
+                tls_options.client_cas = load_certs(path)?;
+                if tls_options.client_cas.is_empty() {
+                    return Err("no certificate found in client_ca".into());
+                }
+            }
+            let acme = match opt.acme_directory {
+                Some(directory_url) => {
+                    let mut tls_options = ClientTlsOptions::default();
+                    for path in opt.acme_ca_certs {
+                        tls_options.ca_certs.extend(load_certs(path)?);
+                    }
+                    Some(AcmeConfig {
+                        directory_url,
+                        domains: opt.acme_domains,
+                        contact: opt.acme_contacts,
+                        cache_dir: opt.acme_cache,
+                        challenge: match opt.acme_http_addr {
+                            Some(addr) => AcmeChallenge::Http01(addr),
+                            None => AcmeChallenge::TlsAlpn01,
+                        },
+                        tls_options,
+                    })
+                }
+                None => None,
+            };
+            let mut server = Server::new(
+                opt.listen_addr,
+                opt.fullchain_path,
+                opt.private_key_path,
+                opt.authorization,
+                opt.transport,
+                opt.routes,
+                tls_options,
+                opt.cert_dir,
+                key_passphrase,
+                acme,
+                opt.decoy,
+            )?;
+            server.set_trusted_proxies(opt.trusted_proxies);
+            server.set_proxy_protocol(opt.accept_proxy_protocol, opt.send_proxy_protocol);
             server.run().await
         }
         Mode::Client => {
             info!("client listen on {}", opt.listen_addr);
-            let client = Client::new(opt.listen_addr, opt.proxy_addr, opt.authorization)?;
+            let mut endpoint = ServerEndpoint::new(&opt.proxy_addr, &opt.ws_path)?;
+            if let Some(host) = opt.host_header {
+                endpoint.set_host(&host)?;
+            }
+            if let Some(sni) = opt.sni {
+                endpoint.sni = sni;
+            }
+            if let Some(connect_addr) = opt.connect_addr {
+                endpoint.connect_addr = connect_addr;
+            }
+            endpoint.protocol = opt.ws_protocol;
+            let mut tls_options = ClientTlsOptions {
+                danger_accept_invalid_certs: opt.danger_accept_invalid_certs,
+                ..Default::default()
+            };
+            if let Some(preset) = opt.tls_preset {
+                preset.apply(&mut tls_options);
+            }
+            if !opt.client_alpn.is_empty() {
+                tls_options.alpn = opt
+                    .client_alpn
+                    .iter()
+                    .map(|p| p.as_bytes().to_vec())
+                    .collect();
+            }
+            if !opt.client_ciphers.is_empty() {
+                tls_options.cipher_suites = opt.client_ciphers;
+            }
+            if opt.client_no_session_resumption {
+                tls_options.session_tickets = false;
+                tls_options.session_cache_size = 0;
+            }
+            if opt.client_no_sni {
+                tls_options.enable_sni = false;
+            }
+            for path in opt.ca_certs {
+                tls_options.ca_certs.extend(load_certs(path)?);
+            }
+            for pin in &opt.spki_pins {
+                tls_options.spki_pins.push(parse_spki_pin(pin)?);
+            }
+            match (opt.client_cert_path, opt.client_key_path) {
+                (Some(cert), Some(key)) => {
+                    tls_options.client_cert = Some((
+                        load_certs(cert)?,
+                        load_private_key(key, key_passphrase.as_deref())?,
+                    ));
+                }
+                (None, None) => {}
+                _ => return Err("client_cert and client_key have to be given together".into()),
+            }
+            if tls_options.danger_accept_invalid_certs {
+                warn!("server certificate verification is disabled");
+            }
+            let mut client = Client::new(
+                opt.listen_addr,
+                endpoint,
+                opt.authorization.clone(),
+                opt.transport,
+                tls_options,
+            )?;
+            client.set_accept_proxy_protocol(opt.accept_proxy_protocol);
+            for spec in &opt.servers {
+                let authorization = match &spec.authorization {
+                    Some(authorization) => authorization.clone(),
+                    None => opt.authorization.clone(),
+                };
+                client.add_server(spec.endpoint()?, authorization, spec.domains.clone())?;
+            }
+            let seconds = |s| Some(Duration::from_secs(s)).filter(|d| !d.is_zero());
+            client.set_pool_timeouts(
+                seconds(opt.pool_idle_timeout),
+                seconds(opt.pool_max_lifetime),
+            );
+            client.set_pool_min_idle(opt.pool_min_idle);
+            client.set_pool_max_size(
+                Some(opt.pool_max_size).filter(|n| *n > 0),
+                Duration::from_secs(opt.pool_acquire_timeout),
+            );
+            client.set_retry(
+                RetryPolicy {
+                    max_retries: opt.connect_retries,
+                    base_delay: Duration::from_millis(opt.connect_backoff_ms),
+                    max_delay: Duration::from_millis(opt.connect_backoff_max_ms),
+                },
+                CircuitBreaker::new(
+                    opt.breaker_threshold,
+                    Duration::from_secs(opt.breaker_open_secs),
+                ),
+            );
+            client.set_pool_stats_interval(
+                Some(Duration::from_secs(opt.pool_stats_interval)).filter(|d| !d.is_zero()),
+            );
             client.run().await
         }
     }
 }
+
+fn gen_cert(
+    sans: &[String],
+    cert_out: PathBuf,
+    key_out: PathBuf,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (cert, key) = generate_self_signed(sans)?;
+    write_file(&cert_out, cert.as_bytes(), force, false)?;
+    write_file(&key_out, key.as_bytes(), force, true)?;
+    // read back the way the server does
+    let certs = load_certs(cert_out.clone())?;
+    load_private_key(key_out.clone(), None)?;
+    let pin = base64::encode(spki_sha256(&certs[0].0)?);
+    println!(
+        "certificate written to {:?}, key to {:?}",
+        cert_out, key_out
+    );
+    println!(
+        "clients trust it with --ca_cert {:?} or --pin_sha256 {}",
+        cert_out, pin
+    );
+    Ok(())
+}
+
+/// `private` files are only readable by the owner
+fn write_file(path: &Path, data: &[u8], force: bool, private: bool) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            let message = format!("{:?} exists, pass --force to overwrite it", path);
+            return std::io::Error::new(e.kind(), message);
+        }
+        e
+    })?;
+    // the mode only applies to new files, not to one overwritten with --force
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
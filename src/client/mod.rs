@@ -1,4 +1,6 @@
-use std::{convert::TryInto, sync::Arc};
+pub mod upstream;
+
+use std::{convert::TryInto, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::{FutureExt};
 
@@ -6,51 +8,228 @@ use futures::SinkExt;
 use log::{error, info};
 use tokio::{
     io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 use tokio_tungstenite::{tungstenite::Message};
 
+use crate::client::upstream::{Upstream, Upstreams};
 use crate::pool::Pool;
-use crate::transport::WebSocketConnection;
+use crate::transport::{
+    quic::{QuicConnector, UdpAssociation},
+    tls::ClientTlsOptions,
+    ServerEndpoint, TransportType, WebSocketConnection,
+};
 use crate::{
     codec::Packet,
-    pool::make_connection::{MakeWebsocketStreamConnection, WebSocketOutboundConnection},
+    pool::{
+        make_connection::{
+            EndpointKey, MakeWebsocketStreamConnection, WebSocketOutboundConnection,
+        },
+        retry::{CircuitBreaker, RetryPolicy},
+    },
 };
 use crate::{
     codec::{
-        Addr, {Command, RepCode},
+        proxy_protocol, Addr, {Command, RepCode},
     },
     error::{ProxyError, ProxyResult},
 };
 
 pub struct Client {
     listen_addr: String,
-    mt: MakeWebsocketStreamConnection,
+    // the server given to `new` and those added by `add_server`
+    upstreams: Upstreams,
+    transport: TransportType,
+    tls_options: ClientTlsOptions,
+    quic: Option<QuicConnector>,
+    // local connections start with a PROXY protocol header
+    accept_proxy_protocol: bool,
+    // pooled websockets unused this long are closed
+    pool_idle_timeout: Option<Duration>,
+    // pooled websockets are not reused after this long
+    pool_max_lifetime: Option<Duration>,
+    // websockets opened ahead of sessions
+    pool_min_idle: usize,
+    // sessions wait for a websocket instead of opening more than this
+    pool_max_size: Option<usize>,
+    pool_acquire_timeout: Duration,
+    // how often pool statistics are logged
+    pool_stats_interval: Option<Duration>,
 }
 
 impl Client {
     pub fn new(
         listen_addr: String,
-        proxy_addr: String,
+        endpoint: ServerEndpoint,
         authorization: String,
+        transport: TransportType,
+        tls_options: ClientTlsOptions,
     ) -> ProxyResult<Self> {
-        if proxy_addr.is_empty() {
-            return Err(ProxyError::EmptyParams);
-        }
+        let quic = match transport {
+            TransportType::Quic => {
+                let mut quic =
+                    QuicConnector::new(endpoint.connect_addr.clone(), authorization.clone());
+                quic.set_server_name(endpoint.sni.clone());
+                quic.set_route(endpoint.url.path().to_string(), endpoint.protocol.clone());
+                quic.set_tls_options(&tls_options)?;
+                Some(quic)
+            }
+            TransportType::WebSocket | TransportType::Http2 => None,
+        };
+        let mt = MakeWebsocketStreamConnection::new(
+            endpoint,
+            authorization,
+            transport == TransportType::Http2,
+            &tls_options,
+        )?;
         Ok(Self {
             listen_addr,
-            mt: MakeWebsocketStreamConnection {
-                server_url: Arc::new(format!("wss://{}", proxy_addr)),
-                authorization: Arc::new(authorization),
-            },
+            upstreams: Upstreams::new(mt),
+            transport,
+            tls_options,
+            quic,
+            accept_proxy_protocol: false,
+            pool_idle_timeout: None,
+            pool_max_lifetime: None,
+            pool_min_idle: 0,
+            pool_max_size: None,
+            pool_acquire_timeout: Duration::from_secs(30),
+            pool_stats_interval: None,
         })
     }
 
+    /// require a PROXY protocol header (v1 or v2) on local connections, e.g. behind haproxy
+    pub fn set_accept_proxy_protocol(&mut self, accept: bool) {
+        self.accept_proxy_protocol = accept;
+    }
+
+    /// close pooled websockets before load balancers in between reap them
+    pub fn set_pool_timeouts(
+        &mut self,
+        idle_timeout: Option<Duration>,
+        max_lifetime: Option<Duration>,
+    ) {
+        self.pool_idle_timeout = idle_timeout;
+        self.pool_max_lifetime = max_lifetime;
+    }
+
+    /// keep `min_idle` websockets to each server open ahead of time so sessions skip the handshake
+    pub fn set_pool_min_idle(&mut self, min_idle: usize) {
+        self.pool_min_idle = min_idle;
+    }
+
+    /// open at most `max_size` websockets, further sessions wait up to `acquire_timeout` for one
+    pub fn set_pool_max_size(&mut self, max_size: Option<usize>, acquire_timeout: Duration) {
+        self.pool_max_size = max_size;
+        self.pool_acquire_timeout = acquire_timeout;
+    }
+
+    /// log pool statistics every `interval`, e.g. to size the pool
+    pub fn set_pool_stats_interval(&mut self, interval: Option<Duration>) {
+        self.pool_stats_interval = interval;
+    }
+
+    /// also send sessions to another server, those to `domains` (and their subdomains) only,
+    /// or any not matched by another server when empty, websocket and h2 transports only
+    pub fn add_server(
+        &mut self,
+        endpoint: ServerEndpoint,
+        authorization: String,
+        domains: Vec<String>,
+    ) -> ProxyResult<()> {
+        if self.transport == TransportType::Quic {
+            return Err(ProxyError::UnsupportedByTransport(
+                "several servers".to_string(),
+            ));
+        }
+        let primary = self.upstreams.primary();
+        let mut mt = MakeWebsocketStreamConnection::new(
+            endpoint,
+            authorization,
+            self.transport == TransportType::Http2,
+            &self.tls_options,
+        )?;
+        mt.retry = primary.retry;
+        mt.breaker = Arc::new(CircuitBreaker::new(
+            primary.breaker.failure_threshold,
+            primary.breaker.open_for,
+        ));
+        self.upstreams.push(Upstream { mt, domains });
+        Ok(())
+    }
+
+    /// how failed connections to the servers are retried, and when to stop trying for a while,
+    /// every server gets a breaker like `breaker` of its own
+    pub fn set_retry(&mut self, retry: RetryPolicy, breaker: CircuitBreaker) {
+        for upstream in self.upstreams.iter_mut() {
+            upstream.mt.retry = retry;
+            upstream.mt.breaker = Arc::new(CircuitBreaker::new(
+                breaker.failure_threshold,
+                breaker.open_for,
+            ));
+        }
+    }
+
+    // the address behind a load balancer sending PROXY protocol headers, the tcp peer otherwise
+    async fn peer_addr(
+        inbound: &mut TcpStream,
+        accept_proxy_protocol: bool,
+    ) -> ProxyResult<SocketAddr> {
+        let peer = inbound.peer_addr()?;
+        if !accept_proxy_protocol {
+            return Ok(peer);
+        }
+        Ok(proxy_protocol::read_header(inbound)
+            .await?
+            .map(|header| header.source)
+            .unwrap_or(peer))
+    }
+
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(self.listen_addr.clone()).await?;
-        let pool: Pool<WebSocketOutboundConnection> = Pool::new(10);
+        if let Some(quic) = &self.quic {
+            while let Ok((inbound, _)) = listener.accept().await {
+                let accept_proxy_protocol = self.accept_proxy_protocol;
+                let serve =
+                    Client::serve_quic(inbound, quic.clone(), accept_proxy_protocol).map(|r| {
+                        if let Err(e) = r {
+                            error!("Failed to transfer; error={:?}", e);
+                        }
+                    });
+                tokio::spawn(serve);
+            }
+            return Ok(());
+        }
+
+        let pool: Pool<EndpointKey, WebSocketOutboundConnection> =
+            Pool::new(10, self.pool_idle_timeout, self.pool_max_lifetime);
+        pool.set_max_size(self.pool_max_size, self.pool_acquire_timeout);
+        if self.pool_min_idle > 0 {
+            for upstream in self.upstreams.iter() {
+                let mt = upstream.mt.clone();
+                pool.set_min_idle(mt.key(), self.pool_min_idle, mt);
+            }
+        }
+        let upstreams = Arc::new(self.upstreams.clone());
+        if let Some(interval) = self.pool_stats_interval {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    info!("pool stats, detail is {:?}", pool.stats());
+                }
+            });
+        }
         while let Ok((inbound, _)) = listener.accept().await {
-            let serve = Client::serve(inbound, self.mt.clone(), pool.clone()).map(|r| {
+            let serve = Client::serve(
+                inbound,
+                upstreams.clone(),
+                pool.clone(),
+                self.accept_proxy_protocol,
+            )
+            .map(|r| {
                 if let Err(e) = r {
                     error!("Failed to transfer; error={:?}", e);
                 }
@@ -62,28 +241,144 @@ impl Client {
 
     async fn serve(
         mut inbound: TcpStream,
-        mt: MakeWebsocketStreamConnection,
-        pool: Pool<WebSocketOutboundConnection>,
+        upstreams: Arc<Upstreams>,
+        pool: Pool<EndpointKey, WebSocketOutboundConnection>,
+        accept_proxy_protocol: bool,
     ) -> ProxyResult<()> {
-        info!("Get new connections");
+        let peer = Client::peer_addr(&mut inbound, accept_proxy_protocol).await?;
+        info!("Get new connections from {}", peer);
 
         // socks5 handshake: decide which method to use
         let (cmd, addr) = Client::socks5_handshake(&mut inbound).await?;
         info!("cmd {:?} addr {:?}", cmd, addr);
+        if cmd != Command::Connect {
+            Client::socks5_reply(&mut inbound, RepCode::UnsupportedCommand, &addr).await?;
+            return Err(ProxyError::UnsupportedCommand);
+        }
+        Client::socks5_reply(&mut inbound, RepCode::Success, &addr).await?;
         info!("handshake successfully");
 
-        let mut stream = pool.get(mt).await?;
+        let mt = upstreams.pick(&addr).clone();
+        info!("send {:?} to {}", addr, mt.endpoint.url);
+        let mut stream = pool.get(mt.key(), mt.clone()).await?;
         info!("WebSocket handshake has been successfully completed");
 
-        let outbound = stream.inner.take().unwrap();
-        let mut outbound = WebSocketConnection(outbound.0);
         let addr_msg: Message = Packet::Connect(addr).try_into()?;
-        outbound.0.send(addr_msg).await?;
+        // a pooled connection may die after its check, retry once on another one
+        if let Err(e) = stream.0.send(addr_msg.clone()).await {
+            info!("send connect packet failed, retry, detail is {:?}", e);
+            stream.discard();
+            stream = pool.get(mt.key(), mt).await?;
+            if let Err(e) = stream.0.send(addr_msg).await {
+                stream.discard();
+                return Err(e.into());
+            }
+        }
         info!("send connect packet successfully");
-        let (a_to_b, b_to_a) = copy_bidirectional(&mut outbound, &mut inbound).await?;
-        info!("finished copy data a_to_b {} b_to_a {}", a_to_b, b_to_a);
+        let outbound = stream.inner.take().unwrap();
+        let mut outbound = WebSocketConnection(outbound.0);
+        let copied = copy_bidirectional(&mut outbound, &mut inbound).await;
         let _ = stream.inner.insert(WebSocketOutboundConnection(outbound.0));
-        Ok(())
+        match copied {
+            Ok((a_to_b, b_to_a)) => {
+                info!("finished copy data a_to_b {} b_to_a {}", a_to_b, b_to_a);
+                Ok(())
+            }
+            // cut off before both sides sent their close packet, the server may still be
+            // in the middle of this session
+            Err(e) => {
+                stream.discard();
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn serve_quic(
+        mut inbound: TcpStream,
+        quic: QuicConnector,
+        accept_proxy_protocol: bool,
+    ) -> ProxyResult<()> {
+        let peer = Client::peer_addr(&mut inbound, accept_proxy_protocol).await?;
+        info!("Get new connections from {}", peer);
+
+        let (cmd, addr) = Client::socks5_handshake(&mut inbound).await?;
+        info!("cmd {:?} addr {:?}", cmd, addr);
+        match cmd {
+            Command::Connect => {
+                Client::socks5_reply(&mut inbound, RepCode::Success, &addr).await?;
+                let mut outbound = quic.open(addr).await?;
+                info!("quic stream has been successfully opened");
+                let (a_to_b, b_to_a) = copy_bidirectional(&mut outbound, &mut inbound).await?;
+                info!("finished copy data a_to_b {} b_to_a {}", a_to_b, b_to_a);
+                Ok(())
+            }
+            Command::Udp => {
+                // relay socket listens on the same interface the browser connected to
+                let socket = UdpSocket::bind((inbound.local_addr()?.ip(), 0)).await?;
+                let association = quic.associate().await?;
+                let relay_addr: Addr = socket.local_addr()?.into();
+                Client::socks5_reply(&mut inbound, RepCode::Success, &relay_addr).await?;
+                info!("udp associate on {:?}", relay_addr);
+                // only the socks5 client may use the relay, from the port it announced if any
+                let client = SocketAddr::new(peer.ip(), addr.port());
+                Client::relay_udp(inbound, socket, association, client).await
+            }
+            Command::Bind => {
+                Client::socks5_reply(&mut inbound, RepCode::UnsupportedCommand, &addr).await?;
+                Err(ProxyError::UnsupportedCommand)
+            }
+        }
+    }
+
+    /// relay socks5 udp packets of `client` until the control connection is closed,
+    /// port 0 accepts any port of its ip
+    async fn relay_udp(
+        mut inbound: TcpStream,
+        socket: UdpSocket,
+        mut association: UdpAssociation,
+        client: SocketAddr,
+    ) -> ProxyResult<()> {
+        let mut buf = vec![0u8; 65535];
+        let mut control = [0u8; 1];
+        let mut peer = None;
+        loop {
+            tokio::select! {
+                n = inbound.read(&mut control) => {
+                    if !matches!(n, Ok(n) if n > 0) {
+                        info!("udp associate control connection closed");
+                        return Ok(());
+                    }
+                }
+                r = socket.recv_from(&mut buf) => {
+                    let (n, from) = r?;
+                    let port_matches = client.port() == 0 || from.port() == client.port();
+                    if from.ip() != client.ip() || !port_matches {
+                        info!("drop udp packet from {}, associated with {}", from, client);
+                        continue;
+                    }
+                    // rsv(2) frag(1) addr port data, fragments are not supported
+                    if n < 4 || buf[2] != 0 {
+                        continue;
+                    }
+                    peer = Some(from);
+                    let mut packet = &buf[3..n];
+                    let addr = Addr::decode(&mut packet).await?;
+                    association.send(&addr, packet)?;
+                }
+                reply = association.recv() => {
+                    let (addr, payload) = match reply {
+                        Some(reply) => reply,
+                        None => return Err(ProxyError::Unknown("quic connection closed".to_string())),
+                    };
+                    if let Some(peer) = peer {
+                        let mut packet = vec![0u8, 0, 0];
+                        addr.encode(BufWriter::new(&mut packet)).await?;
+                        packet.extend_from_slice(&payload);
+                        socket.send_to(&packet, peer).await?;
+                    }
+                }
+            }
+        }
     }
 
     async fn socks5_handshake(stream: &mut TcpStream) -> ProxyResult<(Command, Addr)> {
@@ -113,11 +408,15 @@ impl Client {
         input_write.flush().await?;
         let cmd = Command::decode(&mut input_read).await?;
         let addr = Addr::decode(&mut input_read).await?;
-        input_write.write_u8(0x05).await?;
-        input_write.write_u8(RepCode::Success.into()).await?;
-        input_write.write_u8(0x00).await?;
-        addr.encode(input_write).await?;
 
         Ok((cmd, addr))
     }
+
+    async fn socks5_reply(stream: &mut TcpStream, rep: RepCode, addr: &Addr) -> ProxyResult<()> {
+        let mut output = BufWriter::new(stream);
+        output.write_u8(0x05).await?;
+        output.write_u8(rep.into()).await?;
+        output.write_u8(0x00).await?;
+        addr.encode(output).await
+    }
 }
@@ -0,0 +1,228 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    codec::Addr, error::ProxyResult, pool::make_connection::MakeWebsocketStreamConnection,
+    transport::ServerEndpoint,
+};
+
+/// Another server the client sends sessions to,
+/// `proxy.example.com,path=/ws,authorization=token,match=example.com`,
+/// everything but the address is optional, `match` can be repeated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerSpec {
+    /// `host[:port]`, as `--proxy_addr`
+    pub proxy_addr: String,
+    pub path: String,
+    /// the client wide one when unset
+    pub authorization: Option<String>,
+    pub host: Option<String>,
+    pub sni: Option<String>,
+    pub connect_addr: Option<String>,
+    pub protocol: Option<String>,
+    /// destination domains, with their subdomains, sent to this server only
+    pub domains: Vec<String>,
+}
+
+impl FromStr for ServerSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let proxy_addr = parts.next().unwrap_or_default();
+        if proxy_addr.is_empty() || proxy_addr.contains('=') {
+            return Err(format!("server `{}` should start with its address", s));
+        }
+        let mut spec = ServerSpec {
+            proxy_addr: proxy_addr.to_string(),
+            path: "/".to_string(),
+            authorization: None,
+            host: None,
+            sni: None,
+            connect_addr: None,
+            protocol: None,
+            domains: Vec::new(),
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("path", v)) => spec.path = v.to_string(),
+                Some(("authorization", v)) => spec.authorization = Some(v.to_string()),
+                Some(("host", v)) => spec.host = Some(v.to_string()),
+                Some(("sni", v)) => spec.sni = Some(v.to_string()),
+                Some(("connect_addr", v)) => spec.connect_addr = Some(v.to_string()),
+                Some(("protocol", v)) => spec.protocol = Some(v.to_string()),
+                Some(("match", v)) => spec.domains.push(v.to_ascii_lowercase()),
+                _ => return Err(format!("unknown server option `{}`", part)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl ServerSpec {
+    pub fn endpoint(&self) -> ProxyResult<ServerEndpoint> {
+        let mut endpoint = ServerEndpoint::new(&self.proxy_addr, &self.path)?;
+        if let Some(host) = &self.host {
+            endpoint.set_host(host)?;
+        }
+        if let Some(sni) = &self.sni {
+            endpoint.sni = sni.clone();
+        }
+        if let Some(connect_addr) = &self.connect_addr {
+            endpoint.connect_addr = connect_addr.clone();
+        }
+        endpoint.protocol = self.protocol.clone();
+        Ok(endpoint)
+    }
+}
+
+/// A server and the destination domains routed to it, none for any
+#[derive(Clone)]
+pub struct Upstream {
+    pub mt: MakeWebsocketStreamConnection,
+    pub domains: Vec<String>,
+}
+
+impl Upstream {
+    fn matches(&self, host: &str) -> bool {
+        self.domains.iter().any(|domain| {
+            host == domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .map(|sub| sub.ends_with('.'))
+                    .unwrap_or(false)
+        })
+    }
+}
+
+/// Picks the server of each session
+#[derive(Clone)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    // round robin position, shared by clones
+    next: Arc<AtomicUsize>,
+}
+
+impl Upstreams {
+    /// `primary` takes what no other server matches
+    pub fn new(primary: MakeWebsocketStreamConnection) -> Self {
+        Self {
+            upstreams: vec![Upstream {
+                mt: primary,
+                domains: Vec::new(),
+            }],
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn primary(&self) -> &MakeWebsocketStreamConnection {
+        &self.upstreams[0].mt
+    }
+
+    pub fn push(&mut self, upstream: Upstream) {
+        self.upstreams.push(upstream);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Upstream> {
+        self.upstreams.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Upstream> {
+        self.upstreams.iter_mut()
+    }
+
+    /// the servers matching the destination domain, otherwise those matching nothing,
+    /// in turn, skipping servers whose circuit breaker is open while another one is left
+    pub fn pick(&self, addr: &Addr) -> &MakeWebsocketStreamConnection {
+        let host = match addr {
+            Addr::Domain((host, _)) => Some(host.to_ascii_lowercase()),
+            Addr::IpV4(_) | Addr::IpV6(_) => None,
+        };
+        let mut candidates: Vec<&Upstream> = match &host {
+            Some(host) => self.upstreams.iter().filter(|u| u.matches(host)).collect(),
+            None => Vec::new(),
+        };
+        if candidates.is_empty() {
+            candidates = self
+                .upstreams
+                .iter()
+                .filter(|u| u.domains.is_empty())
+                .collect();
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = candidates.len();
+        let upstream = (0..len)
+            .map(|i| candidates[(start + i) % len])
+            .find(|u| !u.mt.breaker.is_open())
+            .unwrap_or(candidates[start % len]);
+        &upstream.mt
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::tls::ClientTlsOptions;
+
+    fn make(spec: &str) -> Upstream {
+        let spec: ServerSpec = spec.parse().unwrap();
+        let mt = MakeWebsocketStreamConnection::new(
+            spec.endpoint().unwrap(),
+            spec.authorization.unwrap_or_default(),
+            false,
+            &ClientTlsOptions::default(),
+        )
+        .unwrap();
+        Upstream {
+            mt,
+            domains: spec.domains,
+        }
+    }
+
+    #[test]
+    fn test_upstreams() {
+        let spec: ServerSpec = "b.example:8443,path=/ws,authorization=t,match=Example.com"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.domains, vec!["example.com"]);
+        let endpoint = spec.endpoint().unwrap();
+        assert_eq!(endpoint.url.as_str(), "wss://b.example:8443/ws");
+        assert!("path=/ws".parse::<ServerSpec>().is_err());
+        assert!("b.example,ttl=1".parse::<ServerSpec>().is_err());
+
+        let mut upstreams = Upstreams::new(make("a.example").mt);
+        upstreams.push(make("b.example,match=example.com"));
+        upstreams.push(make("c.example"));
+        let url = |addr: Addr| {
+            upstreams
+                .pick(&addr)
+                .endpoint
+                .url
+                .host_str()
+                .unwrap()
+                .to_string()
+        };
+        let domain = |host: &str| Addr::Domain((host.to_string(), 443));
+
+        assert_eq!(url(domain("www.EXAMPLE.com")), "b.example");
+        assert_eq!(url(domain("example.com")), "b.example");
+        // everything else goes round robin
+        let picked: Vec<_> = (0..4).map(|_| url(domain("notexample.com"))).collect();
+        assert!(picked.contains(&"a.example".to_string()));
+        assert!(picked.contains(&"c.example".to_string()));
+        assert!(!picked.contains(&"b.example".to_string()));
+
+        // servers which look down are skipped
+        let c = upstreams.iter().nth(2).unwrap();
+        for _ in 0..c.mt.breaker.failure_threshold {
+            c.mt.breaker.failure();
+        }
+        for _ in 0..4 {
+            assert_eq!(url(Addr::IpV4(([10, 0, 0, 1], 443))), "a.example");
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod packet;
+pub mod proxy_protocol;
 pub mod socks5;
 
 pub use packet::Packet;
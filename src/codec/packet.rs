@@ -21,7 +21,21 @@ impl TryFrom<Packet> for Message {
     type Error = ProxyError;
 
     fn try_from(value: Packet) -> ProxyResult<Message> {
-        match value {
+        Ok(Message::binary(value.encode()?))
+    }
+}
+
+impl Packet {
+    pub fn to_packet(msg: Message) -> ProxyResult<Packet> {
+        if !msg.is_binary() {
+            return Err(ProxyError::PacketNotBinaryMessage);
+        }
+        Packet::decode(msg.into_data())
+    }
+
+    /// encode packet into raw bytes, shared by every transport
+    pub fn encode(self) -> ProxyResult<Vec<u8>> {
+        match self {
             Packet::Connect(addr) => {
                 let mut msg = vec![PACKET_CONNECT];
                 match addr {
@@ -41,25 +55,20 @@ impl TryFrom<Packet> for Message {
                         msg.extend(addr);
                     }
                 }
-                Ok(Message::binary(msg))
+                Ok(msg)
             }
             Packet::Data(data) => {
                 let mut msg = Vec::with_capacity(data.len() + 1);
                 msg.push(PACKET_DATA);
                 msg.extend(data);
-                Ok(Message::binary(msg))
+                Ok(msg)
             }
-            Packet::Close() => Ok(Message::binary(vec![PACKET_CLOSE])),
+            Packet::Close() => Ok(vec![PACKET_CLOSE]),
         }
     }
-}
 
-impl Packet {
-    pub fn to_packet(msg: Message) -> ProxyResult<Packet> {
-        if !msg.is_binary() {
-            return Err(ProxyError::PacketNotBinaryMessage);
-        }
-        let mut data = msg.into_data();
+    /// decode packet from raw bytes, shared by every transport
+    pub fn decode(mut data: Vec<u8>) -> ProxyResult<Packet> {
         let mut cursor = Cursor::new(&mut data);
         match cursor.read_u8()? {
             PACKET_CONNECT => {
@@ -68,7 +77,7 @@ impl Packet {
             }
             PACKET_DATA => Ok(Packet::Data(data[1..].into())),
             PACKET_CLOSE => Ok(Packet::Close()),
-            _ => unreachable!(),
+            _ => Err(ProxyError::InvalidPacketType),
         }
     }
 }
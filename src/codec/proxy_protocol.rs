@@ -0,0 +1,239 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::{ProxyError, ProxyResult};
+
+/// first bytes of a v2 header
+pub const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+// longest v1 line allowed by the spec, including the trailing crlf
+const V1_MAX_LEN: usize = 107;
+const V2_VERSION: u8 = 0x20;
+const V2_LOCAL: u8 = 0x00;
+const V2_PROXY: u8 = 0x01;
+const V2_TCP4: u8 = 0x11;
+const V2_UDP4: u8 = 0x12;
+const V2_TCP6: u8 = 0x21;
+const V2_UDP6: u8 = 0x22;
+
+/// PROXY protocol version written to destinations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    /// human readable line
+    V1,
+    /// binary header
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(ProxyProtocolVersion::V1),
+            "v2" | "2" => Ok(ProxyProtocolVersion::V2),
+            _ => Err("Could not parse a proxy protocol version, expected v1 or v2"),
+        }
+    }
+}
+
+/// Addresses of the original connection as told by a load balancer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+impl ProxyHeader {
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            source,
+            destination,
+        }
+    }
+
+    /// encode as `version`, mixed address families are sent as ipv6
+    pub fn encode(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        let (source, destination) = match (self.source, self.destination) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => (self.source, self.destination),
+            _ => (to_v6(self.source), to_v6(self.destination)),
+        };
+        match version {
+            ProxyProtocolVersion::V1 => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes()
+            }
+            ProxyProtocolVersion::V2 => {
+                let mut buf = BytesMut::new();
+                buf.put_slice(V2_SIGNATURE);
+                buf.put_u8(V2_VERSION | V2_PROXY);
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        buf.put_u8(V2_TCP4);
+                        buf.put_u16(12);
+                        buf.put_slice(&src.octets());
+                        buf.put_slice(&dst.octets());
+                    }
+                    (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                        buf.put_u8(V2_TCP6);
+                        buf.put_u16(36);
+                        buf.put_slice(&src.octets());
+                        buf.put_slice(&dst.octets());
+                    }
+                    _ => unreachable!("families are aligned above"),
+                }
+                buf.put_u16(source.port());
+                buf.put_u16(destination.port());
+                buf.to_vec()
+            }
+        }
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        addr => addr,
+    }
+}
+
+fn invalid(detail: &str) -> ProxyError {
+    ProxyError::InvalidProxyHeader(detail.to_string())
+}
+
+/// read the v1 or v2 header a load balancer sends first, it has to be there,
+/// `None` for `UNKNOWN`, `LOCAL` (health checks) and non inet families
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> ProxyResult<Option<ProxyHeader>> {
+    // both versions are at least this long
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        return read_v2(reader).await;
+    }
+    if !start.starts_with(V1_PREFIX) {
+        return Err(invalid("missing header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 line is too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    parse_v1(&line[V1_PREFIX.len()..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> ProxyResult<Option<ProxyHeader>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 line is not ascii"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let addr = |ip: &str, port: &str| -> ProxyResult<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("v1 address"))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid("v1 address does not match its family"));
+                }
+                let port = port.parse().map_err(|_| invalid("v1 port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some(ProxyHeader::new(addr(src, sport)?, addr(dst, dport)?)))
+        }
+        _ => Err(invalid("v1 line")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> ProxyResult<Option<ProxyHeader>> {
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head).await?;
+    let (version_command, family) = (head[0], head[1]);
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    if version_command & 0xf0 != V2_VERSION {
+        return Err(invalid("v2 version"));
+    }
+    // the body is read as a whole even if only the addresses are used, tlvs are skipped
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    match version_command & 0x0f {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("v2 command")),
+    }
+    let mut body = &body[..];
+    let (source, destination) = match family {
+        V2_TCP4 | V2_UDP4 if body.len() >= 12 => {
+            let src = Ipv4Addr::from(body.get_u32());
+            let dst = Ipv4Addr::from(body.get_u32());
+            (IpAddr::V4(src), IpAddr::V4(dst))
+        }
+        V2_TCP6 | V2_UDP6 if body.len() >= 36 => {
+            let src = Ipv6Addr::from(body.get_u128());
+            let dst = Ipv6Addr::from(body.get_u128());
+            (IpAddr::V6(src), IpAddr::V6(dst))
+        }
+        V2_TCP4 | V2_UDP4 | V2_TCP6 | V2_UDP6 => return Err(invalid("v2 addresses")),
+        // unspecified or unix sockets carry nothing we could use
+        _ => return Ok(None),
+    };
+    let (sport, dport) = (body.get_u16(), body.get_u16());
+    Ok(Some(ProxyHeader::new(
+        SocketAddr::new(source, sport),
+        SocketAddr::new(destination, dport),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy_header() {
+        let v4 = ProxyHeader::new(
+            "203.0.113.9:51234".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        let v6 = ProxyHeader::new(
+            "[2001:db8::9]:51234".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        );
+        assert_eq!(
+            v4.encode(ProxyProtocolVersion::V1),
+            b"PROXY TCP4 203.0.113.9 10.0.0.1 51234 443\r\n"
+        );
+        for header in [v4, v6] {
+            for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+                // whatever follows the header stays unread
+                let mut data = header.encode(version);
+                data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                let mut reader = &data[..];
+                let parsed = read_header(&mut reader).await.unwrap();
+                assert_eq!(parsed, Some(header));
+                assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+            }
+        }
+
+        // health checks of the load balancer
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[V2_VERSION | V2_LOCAL, 0, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+        let unknown = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut &unknown[..]).await.unwrap(), None);
+
+        let direct = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00";
+        assert!(read_header(&mut &direct[..]).await.is_err());
+        let mismatched = b"PROXY TCP4 2001:db8::9 10.0.0.1 1 2\r\n";
+        assert!(read_header(&mut &mismatched[..]).await.is_err());
+    }
+}
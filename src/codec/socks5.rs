@@ -124,13 +124,22 @@ impl From<RepCode> for u8 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Addr {
     IpV4(([u8; 4], u16)),
     Domain((String, u16)),
     IpV6(([u8; 16], u16)),
 }
 
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Addr::IpV4((addr.ip().octets(), addr.port())),
+            SocketAddr::V6(addr) => Addr::IpV6((addr.ip().octets(), addr.port())),
+        }
+    }
+}
+
 impl TryFrom<Addr> for Vec<SocketAddr> {
     type Error = crate::error::ProxyError;
     fn try_from(a: Addr) -> Result<Vec<SocketAddr>, Self::Error> {
@@ -154,6 +163,12 @@ impl TryFrom<Addr> for Vec<SocketAddr> {
 }
 
 impl Addr {
+    pub fn port(&self) -> u16 {
+        match self {
+            Addr::IpV4((_, port)) | Addr::Domain((_, port)) | Addr::IpV6((_, port)) => *port,
+        }
+    }
+
     pub async fn decode<T>(mut stream: T) -> ProxyResult<Self>
     where
         T: AsyncRead + Unpin,
@@ -170,7 +185,7 @@ impl Addr {
             3 => {
                 let addr_len = stream.read_u8().await?;
                 let mut addr = [0u8; 255];
-                stream.read(&mut addr[..(addr_len as usize)]).await?;
+                stream.read_exact(&mut addr[..(addr_len as usize)]).await?;
                 let port = stream.read_u16().await?;
                 Ok(Addr::Domain((
                     std::str::from_utf8(&addr[..(addr_len as usize)])
@@ -197,17 +212,17 @@ impl Addr {
         match self {
             Addr::IpV4((addr, port)) => {
                 stream.write_u8(0x01).await?;
-                stream.write(addr).await?;
+                stream.write_all(addr).await?;
                 addr_port = *port;
             }
             Addr::Domain((addr, port)) => {
-                stream.write(&[0x03, addr.len() as u8]).await?;
-                stream.write(addr.as_bytes()).await?;
+                stream.write_all(&[0x03, addr.len() as u8]).await?;
+                stream.write_all(addr.as_bytes()).await?;
                 addr_port = *port;
             }
             Addr::IpV6((addr, port)) => {
-                stream.write(&[0x04]).await?;
-                stream.write(addr).await?;
+                stream.write_all(&[0x04]).await?;
+                stream.write_all(addr).await?;
                 addr_port = *port;
             }
         }
@@ -216,21 +231,22 @@ impl Addr {
         Ok(())
     }
 
+    /// layout matches `from_bytes`: addr type, little endian port, addr
     pub fn to_bytes(&self, bytes: &mut BytesMut) {
         match self {
             Addr::IpV4(addr) => {
                 bytes.put_u8(ADDR_IPV4);
-                bytes.put_u16(addr.1);
+                bytes.put_u16_le(addr.1);
                 bytes.put_slice(&addr.0[..]);
             }
             Addr::Domain(addr) => {
                 bytes.put_u8(ADDR_DOMAIN);
-                bytes.put_u16(addr.1);
+                bytes.put_u16_le(addr.1);
                 bytes.put(addr.0.as_bytes());
             }
             Addr::IpV6(addr) => {
                 bytes.put_u8(ADDR_IPV6);
-                bytes.put_u16(addr.1);
+                bytes.put_u16_le(addr.1);
                 bytes.put_slice(&addr.0[..]);
             }
         }
@@ -251,26 +267,29 @@ impl Addr {
         let addr_type = byteorder::ReadBytesExt::read_u8(&mut cursor)?;
         let port = byteorder::ReadBytesExt::read_u16::<LittleEndian>(&mut cursor)?;
 
-        let addr: Addr;
-        match addr_type {
+        let addr = match addr_type {
             1 => {
-                assert!(bytes.len() == 7);
+                if bytes.len() != 7 {
+                    return Err(ProxyError::UnsupportedAddrType);
+                }
                 let mut ipv4 = [0; 4];
                 std::io::Read::read_exact(&mut cursor, &mut ipv4)?;
-                addr = Addr::IpV4((ipv4, port));
+                Addr::IpV4((ipv4, port))
             }
             3 => {
                 let domain = String::from_utf8_lossy(&bytes[cursor.position() as _..]);
-                addr = Addr::Domain((domain.into(), port));
+                Addr::Domain((domain.into(), port))
             }
             4 => {
-                assert!(bytes.len() == 19);
+                if bytes.len() != 19 {
+                    return Err(ProxyError::UnsupportedAddrType);
+                }
                 let mut ipv6 = [0; 16];
                 std::io::Read::read_exact(&mut cursor, &mut ipv6)?;
-                addr = Addr::IpV6((ipv6, port));
+                Addr::IpV6((ipv6, port))
             }
-            _ => unreachable!(),
-        }
+            _ => return Err(ProxyError::UnsupportedAddrType),
+        };
         info!("addr is {:?}", addr);
         Ok(addr)
     }
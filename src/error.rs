@@ -1,5 +1,5 @@
 use rustls::TLSError;
-use std::io;
+use std::{io, time::Duration};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -30,8 +30,18 @@ pub enum ProxyError {
     // cert start
     #[error("invalid private key")]
     InvalidPrivateKey,
+    #[error("no private key found, pem items are {0:?}")]
+    NoPrivateKey(Vec<String>),
+    #[error("private key is encrypted, give a passphrase")]
+    KeyPassphraseRequired,
+    #[error("pkcs8 error, detail is `{0}`")]
+    Pkcs8Error(#[from] pkcs8::Error),
     #[error("invalid cert")]
     InvalidCert,
+    #[error("unsupported tls option, detail is `{0}`")]
+    UnsupportedTlsOption(String),
+    #[error("the transport does not support {0}")]
+    UnsupportedByTransport(String),
     // end
     #[error("invalid server status, (expected {expected:?}, found {found:?})")]
     InvalidServerStatus { expected: String, found: String },
@@ -43,6 +53,50 @@ pub enum ProxyError {
     AnyhowError(#[from] anyhow::Error),
     #[error("tungstenite error")]
     TungsteniteError(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("h2 error")]
+    H2Error(#[from] h2::Error),
+    #[error("parse http message error")]
+    HttpParseError(#[from] httparse::Error),
+    #[error("invalid http message, detail is `{0}`")]
+    InvalidHttpMessage(String),
+    #[error("invalid proxy protocol header, detail is `{0}`")]
+    InvalidProxyHeader(String),
+    // acme start
+    #[error("acme error, detail is `{0}`")]
+    AcmeError(String),
+    #[error("json error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("generate certificate error")]
+    RcgenError(#[from] rcgen::RcgenError),
+    // end
+    // quic start
+    #[error("quic tls error")]
+    QuicTlsError(#[from] quinn::rustls::Error),
+    #[error("quic no initial cipher suite")]
+    QuicCipherSuiteError(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+    #[error("quic connect error")]
+    QuicConnectError(#[from] quinn::ConnectError),
+    #[error("quic connection error")]
+    QuicConnectionError(#[from] quinn::ConnectionError),
+    #[error("quic write error")]
+    QuicWriteError(#[from] quinn::WriteError),
+    #[error("quic read error")]
+    QuicReadError(#[from] quinn::ReadExactError),
+    #[error("quic stream closed")]
+    QuicClosedStream(#[from] quinn::ClosedStream),
+    #[error("quic read to end error")]
+    QuicReadToEndError(#[from] quinn::ReadToEndError),
+    #[error("quic send datagram error")]
+    QuicSendDatagramError(#[from] quinn::SendDatagramError),
+    #[error("invalid datagram")]
+    InvalidDatagram,
+    #[error("invalid quic hello")]
+    InvalidQuicHello,
+    // end
+    #[error("no pooled connection within {waited:?}, all {max_size} are in use")]
+    PoolTimeout { waited: Duration, max_size: usize },
+    #[error("server looks down, next attempt in {retry_in:?}")]
+    CircuitOpen { retry_in: Duration },
     #[error("reunite read/write stream error")]
     ReuniteError,
     #[error("the data for key `{0}` is not available")]
@@ -1,3 +1,6 @@
+// tungstenite errors and its handshake callback responses are large by design
+#![allow(clippy::result_large_err)]
+
 pub mod client;
 pub mod codec;
 pub mod error;
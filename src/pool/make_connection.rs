@@ -1,34 +1,247 @@
-use std::{pin::Pin, sync::Arc, task::Poll};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, task::noop_waker_ref, Stream};
 use http::Request;
+use log::info;
 use pin_project::pin_project;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{protocol::Role, Error as WsError},
+    WebSocketStream,
+};
 use tower::Service;
 
+use crate::{
+    error::{ProxyError, ProxyResult},
+    pool::{
+        retry::{self, CircuitBreaker, RetryPolicy},
+        GracefulClose, HealthCheck,
+    },
+    transport::{
+        h2::H2Connector,
+        polling::PollingConnector,
+        tls::{self, ClientTlsOptions, ALPN_H2, ALPN_HTTP1},
+        BoxStream, ServerEndpoint,
+    },
+};
+
+/// Pool key of a server and the credentials used on it,
+/// only connections of equal keys are shared
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct EndpointKey {
+    pub endpoint: Arc<ServerEndpoint>,
+    pub authorization: Arc<String>,
+}
+
 #[pin_project]
-pub struct WebSocketOutboundConnection(#[pin] pub WebSocketStream<MaybeTlsStream<TcpStream>>);
+pub struct WebSocketOutboundConnection(#[pin] pub WebSocketStream<BoxStream>);
+
+// an idle tunnel has nothing to read, a close frame, an error or eof means the server is gone
+impl HealthCheck for WebSocketOutboundConnection {
+    fn is_healthy(&mut self) -> bool {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        loop {
+            match Pin::new(&mut self.0).poll_next(&mut cx) {
+                Poll::Pending => return true,
+                // pongs are queued by tungstenite and flushed on the next write
+                Poll::Ready(Some(Ok(msg))) if msg.is_ping() || msg.is_pong() => continue,
+                Poll::Ready(msg) => {
+                    info!("idle websocket is broken, detail is {:?}", msg);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+// a close frame lets the server finish the connection instead of seeing it reset
+impl GracefulClose for WebSocketOutboundConnection {
+    fn close(mut self) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            if let Err(e) = self.0.close(None).await {
+                info!("close idle websocket failed, detail is {:?}", e);
+            }
+        })
+    }
+}
+
+/// how long connections use polling after a websocket upgrade failed, before trying it again
+pub const POLLING_RETRY_UPGRADE: Duration = Duration::from_secs(300);
+
+/// Whether the websocket upgrade is skipped for polling, a failed upgrade,
+/// e.g. a single 502 while the cdn deploys, is tried again after `retry_upgrade`
+#[derive(Debug)]
+pub struct PollingFallback {
+    pub retry_upgrade: Duration,
+    // when the last upgrade failed
+    since: Mutex<Option<Instant>>,
+}
+
+impl PollingFallback {
+    pub fn new(retry_upgrade: Duration) -> Self {
+        Self {
+            retry_upgrade,
+            since: Mutex::new(None),
+        }
+    }
+
+    pub fn is_preferred(&self) -> bool {
+        matches!(*self.since.lock().unwrap(), Some(since) if since.elapsed() < self.retry_upgrade)
+    }
+
+    /// the upgrade failed and polling worked
+    pub fn prefer(&self) {
+        *self.since.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// the upgrade worked again
+    pub fn upgraded(&self) {
+        *self.since.lock().unwrap() = None;
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct MakeWebsocketStreamConnection {
-    pub server_url: Arc<String>,
+    pub endpoint: Arc<ServerEndpoint>,
     pub authorization: Arc<String>,
+    pub tls_connector: TlsConnector,
+    // websockets are opened over http/2 when set
+    pub h2: Option<H2Connector>,
+    // fallback when the websocket upgrade does not make it through
+    pub polling: PollingConnector,
+    // set once polling worked, so later connections skip the upgrade attempt for a while
+    pub prefer_polling: Arc<PollingFallback>,
+    // transient failures are tried again after a backoff
+    pub retry: RetryPolicy,
+    // shared by all clones, so every session fails fast while the server is down
+    pub breaker: Arc<CircuitBreaker>,
 }
 
 impl MakeWebsocketStreamConnection {
-    pub fn new(server_url: String, authorization: String) -> Self {
-        Self {
-            server_url: Arc::new(server_url),
+    pub fn new(
+        endpoint: ServerEndpoint,
+        authorization: String,
+        http2: bool,
+        tls_options: &ClientTlsOptions,
+    ) -> ProxyResult<Self> {
+        let http1_connector = tls::connector(tls::client_config(&[ALPN_HTTP1], tls_options)?);
+        let (tls_connector, h2) = if http2 {
+            let tls_connector = tls::connector(tls::client_config(&[ALPN_H2], tls_options)?);
+            (tls_connector.clone(), Some(H2Connector::new(tls_connector)))
+        } else {
+            (http1_connector.clone(), None)
+        };
+        Ok(Self {
+            endpoint: Arc::new(endpoint),
             authorization: Arc::new(authorization),
+            polling: PollingConnector::new(http1_connector),
+            prefer_polling: Arc::new(PollingFallback::new(POLLING_RETRY_UPGRADE)),
+            retry: RetryPolicy::default(),
+            breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(10))),
+            tls_connector,
+            h2,
+        })
+    }
+
+    /// key of the connections made, for `Pool`
+    pub fn key(&self) -> EndpointKey {
+        EndpointKey {
+            endpoint: self.endpoint.clone(),
+            authorization: self.authorization.clone(),
+        }
+    }
+
+    async fn connect_with_retry(self) -> ProxyResult<WebSocketOutboundConnection> {
+        let mut attempt = 0;
+        loop {
+            self.breaker.check()?;
+            let e = match self.clone().connect().await {
+                Ok(connection) => {
+                    self.breaker.success();
+                    return Ok(connection);
+                }
+                Err(e) if retry::is_transient(&e) => e,
+                // e.g. a bad certificate, says nothing about the server being up,
+                // an open breaker lets the next probe through
+                Err(e) => {
+                    self.breaker.inconclusive();
+                    return Err(e);
+                }
+            };
+            self.breaker.failure();
+            if attempt >= self.retry.max_retries {
+                return Err(e);
+            }
+            let delay = self.retry.backoff(attempt);
+            info!(
+                "connect to server failed, retry in {:?}, detail is {:?}",
+                delay, e
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn connect(self) -> ProxyResult<WebSocketOutboundConnection> {
+        if let Some(h2) = &self.h2 {
+            let ws_stream = h2
+                .open_websocket(&self.endpoint, &self.authorization)
+                .await?;
+            return Ok(WebSocketOutboundConnection(ws_stream));
         }
+
+        if self.prefer_polling.is_preferred() {
+            return self.connect_polling().await;
+        }
+
+        let tls = tls::connect(&self.tls_connector, &self.endpoint).await?;
+        // the handshake takes the Host header from the uri authority
+        let mut req = Request::builder()
+            .uri(self.endpoint.url.as_str())
+            .header("Authorization", self.authorization.as_ref());
+        if let Some(protocol) = &self.endpoint.protocol {
+            req = req.header("Sec-WebSocket-Protocol", protocol.as_str());
+        }
+        let req = req.body(())?;
+        match client_async(req, Box::new(tls) as BoxStream).await {
+            Ok((ws_stream, _)) => {
+                self.prefer_polling.upgraded();
+                Ok(WebSocketOutboundConnection(ws_stream))
+            }
+            // the server or something in between answered without switching protocols
+            Err(e @ (WsError::Http(_) | WsError::Protocol(_))) => {
+                info!(
+                    "websocket upgrade failed, fall back to polling, detail is {:?}",
+                    e
+                );
+                let connection = self.connect_polling().await?;
+                self.prefer_polling.prefer();
+                Ok(connection)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn connect_polling(&self) -> ProxyResult<WebSocketOutboundConnection> {
+        let stream = self
+            .polling
+            .open(&self.endpoint, &self.authorization)
+            .await?;
+        let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Client, None).await;
+        Ok(WebSocketOutboundConnection(ws_stream))
     }
 }
 
 impl<T> Service<T> for MakeWebsocketStreamConnection {
     type Response = WebSocketOutboundConnection;
 
-    type Error = tokio_tungstenite::tungstenite::Error;
+    type Error = ProxyError;
 
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -40,15 +253,25 @@ impl<T> Service<T> for MakeWebsocketStreamConnection {
     }
 
     fn call(&mut self, _: T) -> Self::Future {
-        let req = Request::builder()
-            .uri(self.server_url.as_ref())
-            .header("Authorization", self.authorization.as_ref())
-            .body(())
-            .unwrap();
-        Box::pin(async {
-            let (ws_stream, _) = connect_async(req).await?;
-            // Ok(WebSocketStreamConnection(Some(ws_stream)))
-            Ok(WebSocketOutboundConnection(ws_stream))
-        })
+        Box::pin(self.clone().connect_with_retry())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_polling_fallback() {
+        let fallback = PollingFallback::new(Duration::from_millis(100));
+        assert!(!fallback.is_preferred());
+        fallback.prefer();
+        assert!(fallback.is_preferred());
+        // the upgrade is tried again after a while
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!fallback.is_preferred());
+        fallback.prefer();
+        fallback.upgraded();
+        assert!(!fallback.is_preferred());
     }
 }
@@ -1,27 +1,50 @@
 // thx @dyxushuai for all kindness instructions
 pub mod make_connection;
+pub mod retry;
+pub mod service;
 mod started;
+pub mod stats;
 
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
+    future::Future,
+    hash::Hash,
     ops::{Deref, DerefMut},
+    pin::Pin,
     sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
-use futures_util::future;
+use futures_util::future::{self, BoxFuture};
 use log::info;
+
+use crate::error::ProxyError;
 use tokio::sync::oneshot;
 use tower::{Service, ServiceExt};
 
-use crate::pool::started::Started;
+use crate::pool::{started::Started, stats::PoolStats};
+
+/// Connections the pool checks before they are reused
+pub trait HealthCheck {
+    /// cheap readiness check which must not block, broken connections are dropped
+    fn is_healthy(&mut self) -> bool;
+}
+
+/// Connections the pool closes itself once they are too old or idle for too long
+pub trait GracefulClose {
+    /// tell the other side goodbye instead of just dropping the connection
+    fn close(self) -> BoxFuture<'static, ()>;
+}
 
-/// Connection Pool for reuse connections
-pub struct Pool<T> {
+/// Connection Pool for reuse connections, connections are only shared by equal keys
+/// (e.g. server and credentials), a global size limit covers all keys
+pub struct Pool<K, T> {
     // share between threads
-    inner: Arc<Mutex<Inner<T>>>,
+    inner: Arc<Mutex<Inner<K, T>>>,
 }
 
-impl<T> Clone for Pool<T> {
+impl<K, T> Clone for Pool<K, T> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -29,170 +52,780 @@ impl<T> Clone for Pool<T> {
     }
 }
 
-pub struct Pooled<T> {
+pub struct Pooled<K: Eq + Hash, T> {
     pub inner: Option<T>,
-    pool: Weak<Mutex<Inner<T>>>,
+    key: K,
+    // when the connection was made, for max_lifetime
+    created: Instant,
+    // dropped instead of put back
+    poisoned: bool,
+    pool: Weak<Mutex<Inner<K, T>>>,
 }
 
-impl<T> AsRef<T> for Pooled<T> {
+impl<K: Eq + Hash, T> AsRef<T> for Pooled<K, T> {
     fn as_ref(&self) -> &T {
         self.inner.as_ref().expect("not dropped")
     }
 }
 
-impl<T> AsMut<T> for Pooled<T> {
+impl<K: Eq + Hash, T> AsMut<T> for Pooled<K, T> {
     fn as_mut(&mut self) -> &mut T {
         self.inner.as_mut().expect("not dropped")
     }
 }
 
-impl<T> Deref for Pooled<T> {
+impl<K: Eq + Hash, T> Deref for Pooled<K, T> {
     type Target = T;
     fn deref(&self) -> &T {
         self.as_ref()
     }
 }
 
-impl<T> DerefMut for Pooled<T> {
+impl<K: Eq + Hash, T> DerefMut for Pooled<K, T> {
     fn deref_mut(&mut self) -> &mut T {
         self.as_mut()
     }
 }
 
-impl<T> Pooled<T> {
-    pub fn new(t: T, pool: Weak<Mutex<Inner<T>>>) -> Self {
+impl<K: Eq + Hash, T> Pooled<K, T> {
+    pub fn new(key: K, t: T, pool: Weak<Mutex<Inner<K, T>>>) -> Self {
         Pooled {
             inner: Some(t),
+            key,
+            created: Instant::now(),
+            poisoned: false,
             pool,
         }
     }
+
+    /// never put the connection back, e.g. after an error left it in an unknown state
+    pub fn poison(&mut self) {
+        self.poisoned = true;
+    }
+
+    /// drop the connection now instead of putting it back
+    pub fn discard(mut self) {
+        self.poison();
+    }
 }
 
-impl<T> Drop for Pooled<T> {
+impl<K: Eq + Hash, T> Drop for Pooled<K, T> {
     fn drop(&mut self) {
-        if self.inner.is_none() {
-            info!("inner drop is not some?");
-            return;
+        if let Some(pool) = self.pool.upgrade() {
+            if let Ok(mut pool) = pool.lock() {
+                match self.inner.take() {
+                    Some(t) if self.poisoned => {
+                        info!("discard poisoned connection");
+                        drop(t);
+                        pool.release();
+                    }
+                    Some(t) => {
+                        pool.put(&self.key, t, self.created);
+                        info!("successfully put ws stream back to pool")
+                    }
+                    // taken out and never given back, it is gone
+                    None => {
+                        info!("inner drop is not some?");
+                        pool.release();
+                    }
+                }
+            }
         }
-        let t = self.inner.take().unwrap();
+    }
+}
+
+// what a waiter is handed, a connection or the right to make one
+enum Handoff<T> {
+    Conn(T, Instant),
+    Slot,
+}
+
+// the receiving end of a waiter, a handoff arriving after the caller gave up goes back
+struct Waiting<K: Eq + Hash, T> {
+    key: K,
+    rx: oneshot::Receiver<Handoff<T>>,
+    pool: Weak<Mutex<Inner<K, T>>>,
+}
+
+// nothing in it is pinned structurally
+impl<K: Eq + Hash, T> Unpin for Waiting<K, T> {}
+
+impl<K: Eq + Hash, T> Future for Waiting<K, T> {
+    type Output = Result<Handoff<T>, oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx)
+    }
+}
+
+impl<K: Eq + Hash, T> Drop for Waiting<K, T> {
+    fn drop(&mut self) {
+        self.rx.close();
+        let handoff = match self.rx.try_recv() {
+            Ok(handoff) => handoff,
+            Err(_) => return,
+        };
         if let Some(pool) = self.pool.upgrade() {
             if let Ok(mut pool) = pool.lock() {
-                pool.put(t);
-                info!("successfully put ws stream back to pool")
+                match handoff {
+                    Handoff::Conn(t, created) => pool.put(&self.key, t, created),
+                    Handoff::Slot => pool.release(),
+                }
+            }
+        }
+    }
+}
+
+// what `poll_acquire` holds for a caller until it uses or drops it
+enum Acquired<K: Eq + Hash, T> {
+    Idle(Pooled<K, T>),
+    Slot(Reservation<K, T>),
+}
+
+// a slot counted in `size` for a connection being made, released if the caller
+// gives up before the connection is there
+struct Reservation<K: Eq + Hash, T> {
+    pool: Option<Weak<Mutex<Inner<K, T>>>>,
+}
+
+impl<K: Eq + Hash, T> Reservation<K, T> {
+    // the connection made, or `create_failed`, takes over the slot
+    fn disarm(mut self) {
+        self.pool = None;
+    }
+}
+
+impl<K: Eq + Hash, T> Drop for Reservation<K, T> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take().and_then(|pool| pool.upgrade()) {
+            if let Ok(mut pool) = pool.lock() {
+                info!("release the slot of a connection which was not made");
+                pool.release();
             }
         }
     }
 }
 
-pub struct Inner<T> {
-    idle: Vec<T>,
-    waiters: VecDeque<oneshot::Sender<T>>,
+// a caller in `get`, one which could not reserve a slot waits for one too
+struct Waiter<K, T> {
+    key: K,
+    needs_slot: bool,
+    tx: oneshot::Sender<Handoff<T>>,
+}
+
+struct Idle<T> {
+    value: T,
+    created: Instant,
+    idle_at: Instant,
+}
+
+// creates a connection for prewarming, whatever `MakeConnection` the pool was given
+type Make<T> = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<T>> + Send + Sync>;
+
+// connections of one key
+struct Bucket<T> {
+    idle: Vec<Idle<T>>,
+    // idle connections kept ready once `make` is set
+    min_idle: usize,
+    make: Option<Make<T>>,
+    // prewarming connections being made
+    warming: usize,
+}
+
+impl<T> Bucket<T> {
+    fn new() -> Self {
+        Self {
+            idle: Vec::new(),
+            min_idle: 0,
+            make: None,
+            warming: 0,
+        }
+    }
+}
+
+pub struct Inner<K, T> {
+    buckets: HashMap<K, Bucket<T>>,
+    // callers waiting for a connection of their key or a free slot, oldest first
+    waiters: VecDeque<Waiter<K, T>>,
+    // per key
     max_idle: usize,
+    // connections alive or being made, idle or not, of all keys
+    size: usize,
+    // callers wait for a connection instead of making one once size reaches this
+    max_size: Option<usize>,
+    acquire_timeout: Duration,
+    // idle connections older than this are closed, load balancers drop them anyway
+    idle_timeout: Option<Duration>,
+    // connections are not reused after this long since they were made
+    max_lifetime: Option<Duration>,
+    // `HealthCheck::is_healthy` of `T`, kept here so `Pooled` needs no bound to be dropped
+    is_healthy: fn(&mut T) -> bool,
+    // `GracefulClose::close` of `T`, for the same reason
+    close: fn(T) -> BoxFuture<'static, ()>,
+    // services waiting in `poll_ready` for a connection to come back or a slot to free up
+    ready_wakers: Vec<Waker>,
+    // counters, the gauges are filled in by `Pool::stats`
+    stats: PoolStats,
 }
 
-impl<T> Inner<T> {
-    pub fn put(&mut self, t: T) {
+impl<K: Eq + Hash, T> Inner<K, T> {
+    pub fn put(&mut self, key: &K, mut t: T, created: Instant) {
+        if !(self.is_healthy)(&mut t) {
+            info!("drop broken connection instead of putting it back");
+            self.release();
+            return;
+        }
+        if self.too_old(created, Instant::now()) {
+            info!("close connection which reached max lifetime");
+            self.close_in_background(t);
+            self.release();
+            return;
+        }
         let mut value = Some(t);
-        if !self.waiters.is_empty() {
-            while let Some(waiter) = self.waiters.pop_front() {
-                if !waiter.is_closed() {
-                    let t = value.take().unwrap();
-                    match waiter.send(t) {
-                        Ok(()) => {
-                            break;
-                        }
-                        Err(t) => {
-                            value = Some(t);
-                        }
-                    }
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let waiter = &self.waiters[i];
+            if waiter.tx.is_closed() {
+                self.waiters.remove(i);
+                continue;
+            }
+            if waiter.key != *key {
+                i += 1;
+                continue;
+            }
+            let waiter = self.waiters.remove(i).unwrap();
+            let t = value.take().unwrap();
+            match waiter.tx.send(Handoff::Conn(t, created)) {
+                Ok(()) => {
+                    break;
                 }
+                Err(Handoff::Conn(t, _)) => {
+                    value = Some(t);
+                }
+                Err(Handoff::Slot) => unreachable!(),
             }
         }
 
         if let Some(t) = value {
-            if self.idle.len() < self.max_idle {
-                self.idle.push(t);
+            // callers of other keys are waiting for its slot
+            let needed = self
+                .waiters
+                .iter()
+                .any(|w| w.needs_slot && w.key != *key && !w.tx.is_closed());
+            if self.is_full() && needed {
+                self.close_in_background(t);
+                self.release();
+                return;
+            }
+            match self.buckets.get_mut(key) {
+                Some(bucket) if bucket.idle.len() < self.max_idle => {
+                    bucket.idle.push(Idle {
+                        value: t,
+                        created,
+                        idle_at: Instant::now(),
+                    });
+                    self.wake_ready();
+                }
+                _ => {
+                    self.close_in_background(t);
+                    self.release();
+                }
+            }
+        }
+    }
+
+    // a connection is gone, at max_size the first waiter gets to make a new one
+    fn release(&mut self) {
+        if self.max_size.is_some() {
+            // callers making a connection in a slot of their own wait for nothing more
+            while let Some(i) = self.waiters.iter().position(|w| w.needs_slot) {
+                let waiter = self.waiters.remove(i).unwrap();
+                if waiter.tx.send(Handoff::Slot).is_ok() {
+                    return;
+                }
+            }
+        }
+        self.size -= 1;
+        self.wake_ready();
+    }
+
+    // a connection was made, `started` when making it began
+    fn created(&mut self, started: Instant) {
+        self.stats.created += 1;
+        self.stats.create_latency.record(started.elapsed());
+    }
+
+    fn create_failed(&mut self) {
+        self.stats.create_failures += 1;
+        self.release();
+    }
+
+    fn wake_ready(&mut self) {
+        for waker in self.ready_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        matches!(self.max_size, Some(max) if self.size >= max)
+    }
+
+    // count a connection about to be made if there is room for it
+    fn grow(&mut self) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.size += 1;
+        true
+    }
+
+    // count a connection about to be made, at max_size the slot of
+    // the longest idle connection of any key is taken over if there is one
+    fn reserve(&mut self) -> bool {
+        if self.grow() {
+            return true;
+        }
+        let oldest = self
+            .buckets
+            .values_mut()
+            .filter_map(|bucket| {
+                let (i, idle) = bucket
+                    .idle
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, idle)| idle.idle_at)?;
+                Some((idle.idle_at, i, bucket))
+            })
+            .min_by_key(|(idle_at, _, _)| *idle_at);
+        match oldest {
+            Some((_, i, bucket)) => {
+                let idle = bucket.idle.remove(i);
+                info!("close idle connection to make room for another key");
+                self.close_in_background(idle.value);
+                true
             }
+            None => false,
+        }
+    }
+
+    // an idle connection of `key`, stale or broken ones on the way are dropped
+    fn pop_idle(&mut self, key: &K) -> Option<Idle<T>>
+    where
+        K: Clone,
+    {
+        let now = Instant::now();
+        // idle connections may have been closed by the server or something in between
+        loop {
+            let bucket = self.buckets.entry(key.clone()).or_insert_with(Bucket::new);
+            let mut idle = bucket.idle.pop()?;
+            if self.is_stale(&idle, now) {
+                self.close_in_background(idle.value);
+                self.release();
+                continue;
+            }
+            if !(self.is_healthy)(&mut idle.value) {
+                info!("drop broken idle connection");
+                self.release();
+                continue;
+            }
+            self.stats.idle_hits += 1;
+            return Some(idle);
+        }
+    }
+
+    fn too_old(&self, created: Instant, now: Instant) -> bool {
+        matches!(self.max_lifetime, Some(max) if now.duration_since(created) >= max)
+    }
+
+    fn is_stale(&self, idle: &Idle<T>, now: Instant) -> bool {
+        self.too_old(idle.created, now)
+            || matches!(self.idle_timeout, Some(max) if now.duration_since(idle.idle_at) >= max)
+    }
+
+    // take out idle connections which timed out or reached max lifetime
+    fn take_stale(&mut self, now: Instant) -> Vec<T> {
+        let mut stale = Vec::new();
+        let mut buckets = std::mem::take(&mut self.buckets);
+        for bucket in buckets.values_mut() {
+            let (old, fresh): (Vec<_>, Vec<_>) = std::mem::take(&mut bucket.idle)
+                .into_iter()
+                .partition(|idle| self.is_stale(idle, now));
+            bucket.idle = fresh;
+            stale.extend(old.into_iter().map(|idle| idle.value));
+        }
+        self.buckets = buckets;
+        stale
+    }
+
+    fn close_in_background(&self, t: T) {
+        // pooled connections may be dropped after the runtime is gone
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn((self.close)(t));
         }
     }
 }
 
-impl<T> Pool<T>
+impl<K, T> Pool<K, T>
 where
-    T: Send + 'static,
+    K: Eq + Hash + Clone + Send + 'static,
+    T: HealthCheck + GracefulClose + Send + 'static,
 {
-    pub fn new(max_idle: usize) -> Self {
+    /// a pool keeping at most `max_idle` connections per key, which are closed after
+    /// `idle_timeout` unused or `max_lifetime` after they were made,
+    /// a reaper task checks every few seconds
+    pub fn new(
+        max_idle: usize,
+        idle_timeout: Option<Duration>,
+        max_lifetime: Option<Duration>,
+    ) -> Self {
         let inner = Arc::new(Mutex::new(Inner {
-            idle: Vec::new(),
+            buckets: HashMap::new(),
             waiters: VecDeque::new(),
             max_idle,
+            size: 0,
+            max_size: None,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout,
+            max_lifetime,
+            is_healthy: T::is_healthy,
+            close: T::close,
+            ready_wakers: Vec::new(),
+            stats: PoolStats::default(),
         }));
+        if let Some(period) = idle_timeout.into_iter().chain(max_lifetime).min() {
+            Pool::spawn_reaper(Arc::downgrade(&inner), period / 2);
+        }
         Self { inner }
     }
 
-    pub async fn get<MT>(&self, mt: MT) -> anyhow::Result<Pooled<T>>
+    /// what the pool holds now and how connections were handed out so far
+    pub fn stats(&self) -> PoolStats {
+        let inner = self.inner.lock().unwrap();
+        PoolStats {
+            idle: inner.buckets.values().map(|bucket| bucket.idle.len()).sum(),
+            waiters: inner
+                .waiters
+                .iter()
+                .filter(|waiter| waiter.needs_slot && !waiter.tx.is_closed())
+                .count(),
+            size: inner.size,
+            ..inner.stats.clone()
+        }
+    }
+
+    /// make at most `max_size` connections for all keys together, further callers wait in turn
+    /// for one to come back, for `acquire_timeout` at most
+    pub fn set_max_size(&self, max_size: Option<usize>, acquire_timeout: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.max_size = max_size;
+        inner.acquire_timeout = acquire_timeout;
+    }
+
+    /// keep `min_idle` connections of `key` made by `mt` ready, starting now,
+    /// so sessions do not wait for the handshake
+    pub fn set_min_idle<MT>(&self, key: K, min_idle: usize, mt: MT)
+    where
+        MT: Service<(), Response = T> + Clone + Send + Sync + 'static,
+        MT::Error: Into<anyhow::Error>,
+        MT::Future: Send + 'static,
+    {
+        let make: Make<T> = Arc::new(move || {
+            let mt = mt.clone();
+            Box::pin(async move { mt.oneshot(()).await.map_err(Into::into) })
+        });
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let max_idle = inner.max_idle;
+            let bucket = inner.buckets.entry(key.clone()).or_insert_with(Bucket::new);
+            bucket.min_idle = min_idle.min(max_idle);
+            bucket.make = Some(make);
+        }
+        Pool::prewarm(&self.inner, &key);
+    }
+
+    // make connections of `key` in the background until min_idle are idle or on their way
+    fn prewarm(inner: &Arc<Mutex<Inner<K, T>>>, key: &K) {
+        let mut guard = inner.lock().unwrap();
+        let (make, wanted) = match guard.buckets.get(key) {
+            Some(Bucket {
+                make: Some(make),
+                min_idle,
+                idle,
+                warming,
+            }) => (make.clone(), min_idle.saturating_sub(idle.len() + warming)),
+            _ => return,
+        };
+        // never at the expense of idle connections of other keys, their prewarm would
+        // take the slots back and connections were made and closed forever
+        let missing = (0..wanted).take_while(|_| guard.grow()).count();
+        if let Some(bucket) = guard.buckets.get_mut(key) {
+            bucket.warming += missing;
+        }
+        drop(guard);
+        for _ in 0..missing {
+            let make = make.clone();
+            let key = key.clone();
+            let inner = Arc::downgrade(inner);
+            tokio::spawn(async move {
+                let started = Instant::now();
+                let result = make().await;
+                if let Some(inner) = inner.upgrade() {
+                    let mut inner = inner.lock().unwrap();
+                    if let Some(bucket) = inner.buckets.get_mut(&key) {
+                        bucket.warming -= 1;
+                    }
+                    match result {
+                        Ok(t) => {
+                            info!("prewarmed connection");
+                            inner.created(started);
+                            inner.put(&key, t, Instant::now());
+                        }
+                        // not retried right away, the next get tries again
+                        Err(e) => {
+                            info!("prewarm connection failed, detail is {:?}", e);
+                            inner.create_failed();
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    // closes stale idle connections until the pool is dropped
+    fn spawn_reaper(inner: Weak<Mutex<Inner<K, T>>>, period: Duration) {
+        let period = period.clamp(Duration::from_millis(100), Duration::from_secs(30));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                let mut guard = inner.lock().unwrap();
+                let stale = guard.take_stale(Instant::now());
+                if !stale.is_empty() {
+                    info!("close {} stale idle connections", stale.len());
+                }
+                for t in stale {
+                    guard.close_in_background(t);
+                    guard.release();
+                }
+                let keys: Vec<K> = guard.buckets.keys().cloned().collect();
+                drop(guard);
+                for key in &keys {
+                    Pool::prewarm(&inner, key);
+                }
+            }
+        });
+    }
+
+    // ready with an idle connection of `key` or a slot to make one in (one of another key
+    // is closed to make room), callers waiting in `get` come first
+    fn poll_acquire(&self, key: &K, cx: &mut Context<'_>) -> Poll<Acquired<K, T>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(idle) = inner.pop_idle(key) {
+            drop(inner);
+            Pool::prewarm(&self.inner, key);
+            let pooled = self.pooled(key.clone(), idle.value, idle.created);
+            return Poll::Ready(Acquired::Idle(pooled));
+        }
+        if inner.reserve() {
+            return Poll::Ready(Acquired::Slot(self.reservation()));
+        }
+        // a task polling again keeps a single waker
+        if !inner.ready_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            inner.ready_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// a connection of `key`, made by `mt` if none is idle
+    pub async fn get<MT>(&self, key: K, mt: MT) -> anyhow::Result<Pooled<K, T>>
     where
         MT: Service<(), Response = T> + Send + 'static,
         MT::Error: Into<anyhow::Error>,
         MT::Future: Unpin + Send + 'static,
     {
-        let rx = {
+        let (waiting, reserved) = {
             let mut inner = self.inner.lock().unwrap();
-            if let Some(idle) = inner.idle.pop() {
+            if let Some(idle) = inner.pop_idle(&key) {
                 info!("get connection from poll");
-                return Ok(Pooled {
-                    inner: Some(idle),
-                    pool: Arc::downgrade(&self.inner),
-                });
+                drop(inner);
+                Pool::prewarm(&self.inner, &key);
+                return Ok(self.pooled(key, idle.value, idle.created));
             }
 
+            let reserved = inner.reserve();
             let (tx, rx) = oneshot::channel();
-            inner.waiters.push_back(tx);
+            inner.waiters.push_back(Waiter {
+                key: key.clone(),
+                needs_slot: !reserved,
+                tx,
+            });
             // try create
             drop(inner);
-            rx
+            Pool::prewarm(&self.inner, &key);
+            let waiting = Waiting {
+                key: key.clone(),
+                rx,
+                pool: Arc::downgrade(&self.inner),
+            };
+            (waiting, reserved.then(|| self.reservation()))
+        };
+
+        let reservation = match reserved {
+            Some(reservation) => reservation,
+            None => {
+                info!("pool is full, wait for a connection");
+                return match self.wait(waiting).await? {
+                    Handoff::Conn(v, created) => {
+                        self.inner.lock().unwrap().stats.waiter_hits += 1;
+                        Ok(self.pooled(key, v, created))
+                    }
+                    Handoff::Slot => self.make(key, mt, self.reservation()).await,
+                };
+            }
         };
 
+        let started = Instant::now();
         let lazy_fut = { || mt.oneshot(()) };
-        match future::select(rx, started::lazy(lazy_fut)).await {
-            future::Either::Left((Ok(v), fut)) => {
+        match future::select(waiting, started::lazy(lazy_fut)).await {
+            future::Either::Left((Ok(Handoff::Conn(v, created)), fut)) => {
                 info!("get connection from waiters");
+                self.inner.lock().unwrap().stats.waiter_hits += 1;
                 if fut.started() {
                     let inner = Arc::downgrade(&self.inner);
+                    let key = key.clone();
                     tokio::spawn(async move {
-                        if let Ok(t) = fut.await {
-                            let _ = Pooled::new(t, inner);
+                        let result = fut.await;
+                        reservation.disarm();
+                        if let Some(inner) = inner.upgrade() {
+                            match result {
+                                Ok(t) => {
+                                    inner.lock().unwrap().created(started);
+                                    drop(Pooled::new(key, t, Arc::downgrade(&inner)));
+                                }
+                                Err(_) => inner.lock().unwrap().create_failed(),
+                            }
                         }
                     });
+                } else {
+                    drop(reservation);
                 }
-                Ok(Pooled::new(v, Arc::downgrade(&self.inner)))
+                Ok(self.pooled(key, v, created))
+            }
+            future::Either::Left((Ok(Handoff::Slot), _)) => {
+                unreachable!("callers with a slot of their own are not handed one")
+            }
+            future::Either::Left((Err(_), fut)) => {
+                let result = fut.await;
+                self.fresh(key, started, result, reservation)
+            }
+            future::Either::Right((result, _)) => self.fresh(key, started, result, reservation),
+        }
+    }
+
+    // wait in line for a connection or a free slot
+    async fn wait(&self, mut waiting: Waiting<K, T>) -> anyhow::Result<Handoff<T>> {
+        let (max_size, acquire_timeout) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.max_size.unwrap_or_default(), inner.acquire_timeout)
+        };
+        if let Ok(Ok(handoff)) = tokio::time::timeout(acquire_timeout, &mut waiting).await {
+            return Ok(handoff);
+        }
+        // something may have been handed over right at the deadline
+        waiting.rx.close();
+        waiting.rx.try_recv().map_err(|_| {
+            ProxyError::PoolTimeout {
+                waited: acquire_timeout,
+                max_size,
             }
-            future::Either::Right((Ok(v), _)) => {
+            .into()
+        })
+    }
+
+    // make a connection in a slot already reserved
+    async fn make<MT>(
+        &self,
+        key: K,
+        mt: MT,
+        reservation: Reservation<K, T>,
+    ) -> anyhow::Result<Pooled<K, T>>
+    where
+        MT: Service<(), Response = T>,
+        MT::Error: Into<anyhow::Error>,
+    {
+        let started = Instant::now();
+        let result = mt.oneshot(()).await;
+        self.fresh(key, started, result, reservation)
+    }
+
+    // hand out a connection made for the caller
+    fn fresh<E: Into<anyhow::Error>>(
+        &self,
+        key: K,
+        started: Instant,
+        result: Result<T, E>,
+        reservation: Reservation<K, T>,
+    ) -> anyhow::Result<Pooled<K, T>> {
+        reservation.disarm();
+        let mut inner = self.inner.lock().unwrap();
+        match result {
+            Ok(v) => {
                 info!("get connection from created");
-                Ok(Pooled::new(v, Arc::downgrade(&self.inner)))
-            },
-            future::Either::Left((Err(e), _)) => Err(e.into()),
-            future::Either::Right((Err(e), _)) => Err(e.into()),
+                inner.created(started);
+                inner.stats.fresh += 1;
+                Ok(self.pooled(key, v, Instant::now()))
+            }
+            Err(e) => {
+                inner.create_failed();
+                Err(e.into())
+            }
+        }
+    }
+
+    // the slot just counted in `size`
+    fn reservation(&self) -> Reservation<K, T> {
+        Reservation {
+            pool: Some(Arc::downgrade(&self.inner)),
+        }
+    }
+
+    fn pooled(&self, key: K, t: T, created: Instant) -> Pooled<K, T> {
+        Pooled {
+            inner: Some(t),
+            key,
+            created,
+            poisoned: false,
+            pool: Arc::downgrade(&self.inner),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::service::PoolLayer;
     use super::*;
+    use futures_util::task::noop_waker_ref;
+    use futures_util::FutureExt;
     use pin_project::pin_project;
     use std::future::Future;
     use std::io;
     use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::task::Context;
     use std::task::Poll;
+    use std::time::Duration;
     use tokio::io::AsyncRead;
     use tokio::io::AsyncWrite;
     use tokio::{
-        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadBuf},
         net::UnixStream,
+        sync::mpsc,
     };
-    use tower::Service;
+    use tower::{Service, ServiceBuilder};
 
     #[pin_project]
     struct MockConnection(#[pin] UnixStream);
@@ -228,7 +861,32 @@ mod test {
         }
     }
 
-    struct MockMakeTransport;
+    // the mock never sends anything, so anything readable means it is gone
+    impl HealthCheck for MockConnection {
+        fn is_healthy(&mut self) -> bool {
+            let mut byte = [0u8; 1];
+            let mut buf = ReadBuf::new(&mut byte);
+            let mut cx = Context::from_waker(noop_waker_ref());
+            Pin::new(&mut self.0)
+                .poll_read(&mut cx, &mut buf)
+                .is_pending()
+        }
+    }
+
+    impl GracefulClose for MockConnection {
+        fn close(mut self) -> BoxFuture<'static, ()> {
+            Box::pin(async move {
+                let _ = self.0.shutdown().await;
+            })
+        }
+    }
+
+    /// every connection reports the lines it reads, `close` makes it hang up
+    #[derive(Clone)]
+    struct MockMakeTransport {
+        created: Arc<AtomicUsize>,
+        events: mpsc::UnboundedSender<String>,
+    }
 
     impl<Key> Service<Key> for MockMakeTransport {
         type Response = MockConnection;
@@ -242,14 +900,25 @@ mod test {
         }
 
         fn call(&mut self, _: Key) -> Self::Future {
-            Box::pin(async {
+            self.created.fetch_add(1, Ordering::SeqCst);
+            let events = self.events.clone();
+            Box::pin(async move {
                 let (tx, rx) = UnixStream::pair()?;
                 tokio::spawn(async move {
-                    let mut line = String::new();
                     let mut rx = BufReader::new(rx);
                     loop {
-                        rx.read_line(&mut line).await.unwrap();
-                        println!("{}iiii", line);
+                        let mut line = String::new();
+                        if rx.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            let _ = events.send("eof".to_string());
+                            return;
+                        }
+                        let close = line == "close\n";
+                        let _ = events.send(line);
+                        if close {
+                            drop(rx);
+                            let _ = events.send("closed".to_string());
+                            return;
+                        }
                     }
                 });
                 Ok(MockConnection(tx))
@@ -257,15 +926,355 @@ mod test {
         }
     }
 
+    fn mock() -> (MockMakeTransport, mpsc::UnboundedReceiver<String>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let mc = MockMakeTransport {
+            created: Arc::new(AtomicUsize::new(0)),
+            events,
+        };
+        (mc, rx)
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_pool() {
-        let pool = Pool::new(10);
-        let mc = MockMakeTransport;
-        let mut tx = pool.get(mc).await.unwrap();
-        (&mut *tx).write(b"hello world\n").await.unwrap();
+        let (mc, mut events) = mock();
+        let pool = Pool::new(10, None, None);
+        let mut tx = pool.get("a", mc).await.unwrap();
+        (*tx).write_all(b"hello world\n").await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), "hello world\n");
         drop(pool);
         drop(tx);
-        tokio::time::sleep(tokio::time::Duration::from_secs(50000)).await;
-        println!("end");
+        // without a pool the connection is closed instead of kept
+        wait_eof(&mut events).await;
+    }
+
+    #[tokio::test]
+    async fn test_pool_evicts_broken() {
+        let (mc, mut events) = mock();
+        let pool = Pool::new(10, None, None);
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        drop(tx);
+        let mut tx = pool.get("a", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 1);
+
+        // closed while in use, not put back
+        tx.write_all(b"close\n").await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), "close\n");
+        assert_eq!(events.recv().await.unwrap(), "closed");
+        drop(tx);
+        assert_eq!(idle(&pool), 0);
+
+        // closed while idle, skipped on get
+        let mut tx = pool.get("a", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+        tx.write_all(b"close\n").await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), "close\n");
+        assert_eq!(events.recv().await.unwrap(), "closed");
+        let broken = tx.inner.take().unwrap();
+        pool.inner
+            .lock()
+            .unwrap()
+            .buckets
+            .get_mut("a")
+            .unwrap()
+            .idle
+            .push(Idle {
+                value: broken,
+                created: tx.created,
+                idle_at: Instant::now(),
+            });
+        let _tx = pool.get("a", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 3);
+    }
+
+    async fn wait_eof(events: &mut mpsc::UnboundedReceiver<String>) {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+        assert_eq!(event.unwrap().unwrap(), "eof");
+    }
+
+    #[tokio::test]
+    async fn test_pool_reaps_stale() {
+        let (mc, mut events) = mock();
+        // closed by the reaper while idle
+        let pool = Pool::new(10, Some(Duration::from_millis(200)), None);
+        drop(pool.get("a", mc.clone()).await.unwrap());
+        assert_eq!(idle(&pool), 1);
+        wait_eof(&mut events).await;
+        assert_eq!(idle(&pool), 0);
+
+        // closed when returned after its lifetime
+        let pool = Pool::new(10, None, Some(Duration::from_millis(200)));
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        drop(tx);
+        assert_eq!(idle(&pool), 0);
+        wait_eof(&mut events).await;
+        let _tx = pool.get("a", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 3);
+    }
+
+    // idle connections of all keys
+    fn idle(pool: &Pool<&'static str, MockConnection>) -> usize {
+        let inner = pool.inner.lock().unwrap();
+        inner.buckets.values().map(|bucket| bucket.idle.len()).sum()
+    }
+
+    // waits until the pool has `idle` idle connections
+    async fn wait_idle(pool: &Pool<&'static str, MockConnection>, n: usize) {
+        for _ in 0..100 {
+            if idle(pool) == n {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("pool never had {} idle connections", n);
+    }
+
+    #[tokio::test]
+    async fn test_pool_prewarm() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_min_idle("a", 2, mc.clone());
+        wait_idle(&pool, 2).await;
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+
+        // the one taken is replaced
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        wait_idle(&pool, 2).await;
+        assert_eq!(mc.created.load(Ordering::SeqCst), 3);
+        drop(tx);
+        wait_idle(&pool, 3).await;
+    }
+
+    #[tokio::test]
+    async fn test_pool_prewarm_max_size() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(3), Duration::from_secs(5));
+        pool.set_min_idle("a", 2, mc.clone());
+        pool.set_min_idle("b", 2, mc.clone());
+        wait_idle(&pool, 3).await;
+
+        // keys do not take the warm connections of each other
+        for _ in 0..3 {
+            Pool::prewarm(&pool.inner, &"a");
+            Pool::prewarm(&pool.inner, &"b");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(idle(&pool), 3);
+        assert_eq!(mc.created.load(Ordering::SeqCst), 3);
+
+        // a caller of another key still does
+        let _c = pool.get("c", mc.clone()).await.unwrap();
+        assert_eq!(idle(&pool), 2);
+        assert_eq!(mc.created.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_pool_max_size() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(1), Duration::from_millis(200));
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        let err = pool.get("a", mc.clone()).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProxyError>(),
+            Some(ProxyError::PoolTimeout { max_size: 1, .. })
+        ));
+
+        // waiters are served in the order they came
+        pool.set_max_size(Some(1), Duration::from_secs(5));
+        let (order_tx, mut order) = mpsc::unbounded_channel();
+        for i in 0..2 {
+            let (pool, mc, order_tx) = (pool.clone(), mc.clone(), order_tx.clone());
+            tokio::spawn(async move {
+                let tx = pool.get("a", mc).await.unwrap();
+                order_tx.send(i).unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(tx);
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        drop(tx);
+        assert_eq!(order.recv().await.unwrap(), 0);
+        assert_eq!(order.recv().await.unwrap(), 1);
+        assert_eq!(mc.created.load(Ordering::SeqCst), 1);
+
+        // a connection which is not given back frees its slot
+        let mut tx = pool.get("a", mc.clone()).await.unwrap();
+        let waiting = tokio::spawn({
+            let (pool, mc) = (pool.clone(), mc.clone());
+            async move { pool.get("a", mc).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(tx.inner.take());
+        drop(tx);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_cancelled_waiter() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(1), Duration::from_secs(5));
+
+        // a connection handed to a waiter which gave up comes back
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        let mut waiting = Box::pin(pool.get("a", mc.clone()));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(tx);
+        drop(waiting);
+        assert_eq!(idle(&pool), 1);
+
+        // and so does a slot
+        let mut tx = pool.get("a", mc.clone()).await.unwrap();
+        let mut waiting = Box::pin(pool.get("a", mc.clone()));
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(tx.inner.take());
+        drop(tx);
+        drop(waiting);
+        assert_eq!(pool.inner.lock().unwrap().size, 0);
+        let _tx = pool.get("a", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_reserved_waiter() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(2), Duration::from_secs(5));
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+
+        // a caller making a connection in its own slot needs no other one
+        let slow = tower::service_fn({
+            let mc = mc.clone();
+            move |_: ()| -> BoxFuture<'static, io::Result<MockConnection>> {
+                let mut mc = mc.clone();
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    mc.call(()).await
+                })
+            }
+        });
+        let making = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get("b", slow).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.stats().waiters, 0);
+        drop(tx);
+        assert_eq!(idle(&pool), 1);
+        making.await.unwrap().unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+        assert_eq!(idle(&pool), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_discard() {
+        let (mc, mut events) = mock();
+        let pool = Pool::new(10, None, None);
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        tx.discard();
+        wait_eof(&mut events).await;
+        assert_eq!(idle(&pool), 0);
+
+        let mut tx = pool.get("a", mc.clone()).await.unwrap();
+        tx.poison();
+        drop(tx);
+        assert_eq!(idle(&pool), 0);
+        assert_eq!(pool.inner.lock().unwrap().size, 0);
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_stats() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(1), Duration::from_secs(5));
+        drop(pool.get("a", mc.clone()).await.unwrap());
+        let tx = pool.get("a", mc.clone()).await.unwrap();
+        let waiting = tokio::spawn({
+            let (pool, mc) = (pool.clone(), mc.clone());
+            async move { pool.get("a", mc).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.stats().waiters, 1);
+        drop(tx);
+        waiting.await.unwrap().unwrap();
+
+        let stats = pool.stats();
+        assert_eq!((stats.idle, stats.waiters, stats.size), (1, 0, 1));
+        assert_eq!((stats.fresh, stats.idle_hits, stats.waiter_hits), (1, 1, 1));
+        assert_eq!((stats.created, stats.create_failures), (1, 0));
+        assert_eq!(stats.create_latency.count(), 1);
+        assert_eq!(stats.reuse_ratio(), Some(2.0 / 3.0));
+    }
+
+    #[tokio::test]
+    async fn test_pool_keys() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(1, None, None);
+        let a1 = pool.get("a", mc.clone()).await.unwrap();
+        let b = pool.get("b", mc.clone()).await.unwrap();
+        let a2 = pool.get("a", mc.clone()).await.unwrap();
+        drop(a1);
+        drop(b);
+        // one idle connection per key
+        drop(a2);
+        assert_eq!(idle(&pool), 2);
+        let b = pool.get("b", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 3);
+
+        // at the global limit the idle connection of another key makes room
+        pool.set_max_size(Some(2), Duration::from_secs(5));
+        let c = pool.get("c", mc.clone()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 4);
+        assert_eq!(idle(&pool), 0);
+
+        // and a connection coming back frees its slot for a caller of another key
+        let waiting = tokio::spawn({
+            let (pool, mc) = (pool.clone(), mc.clone());
+            async move { pool.get("a", mc).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(c);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 5);
+        drop(b);
+    }
+
+    #[tokio::test]
+    async fn test_pool_service() {
+        let (mc, _events) = mock();
+        let pool = Pool::new(10, None, None);
+        pool.set_max_size(Some(1), Duration::from_secs(5));
+        let mut svc = ServiceBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .layer(PoolLayer::new(pool.clone(), "a"))
+            .service(mc.clone());
+        let tx = svc.ready().await.unwrap().call(()).await.unwrap();
+
+        // not ready until the connection comes back
+        assert!(svc.ready().now_or_never().is_none());
+        // polling again keeps a single waker
+        assert!(svc.ready().now_or_never().is_none());
+        assert_eq!(pool.inner.lock().unwrap().ready_wakers.len(), 1);
+        drop(tx);
+        let ready = tokio::time::timeout(Duration::from_secs(5), svc.ready()).await;
+        let tx = ready.unwrap().unwrap().call(()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 1);
+
+        // readiness holds the slot until the call, or until the service is dropped
+        tx.discard();
+        let mut other = svc.clone();
+        svc.ready().await.unwrap();
+        assert!(other.ready().now_or_never().is_none());
+        assert!(pool.get("a", mc.clone()).now_or_never().is_none());
+        drop(svc);
+        let tx = other.ready().await.unwrap().call(()).await.unwrap();
+        assert_eq!(mc.created.load(Ordering::SeqCst), 2);
+        drop(tx);
+        assert_eq!(idle(&pool), 1);
     }
 }
@@ -0,0 +1,215 @@
+use std::{
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use rand::Rng;
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use crate::error::{ProxyError, ProxyResult};
+
+/// How often and how patiently a failed connection to the server is tried again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// attempts after the first one, 0 turns retrying off
+    pub max_retries: u32,
+    /// delay before the first retry, doubled for every further one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// random delay up to the exponential backoff of `attempt` (counted from 0),
+    /// so clients started together do not retry in lockstep
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// errors worth another attempt, the server or something in between may be back in a moment
+pub fn is_transient(e: &ProxyError) -> bool {
+    // tls handshake failures come as InvalidData, a bad certificate stays bad
+    let is_io_transient = |e: &io::Error| e.kind() != io::ErrorKind::InvalidData;
+    match e {
+        ProxyError::Disconnect(e) => is_io_transient(e),
+        ProxyError::TungsteniteError(WsError::Io(e)) => is_io_transient(e),
+        ProxyError::TungsteniteError(e) => {
+            matches!(e, WsError::ConnectionClosed | WsError::AlreadyClosed)
+        }
+        ProxyError::H2Error(e) => e.is_io() || e.is_go_away(),
+        // a reverse proxy or cdn answering for a server which is down
+        ProxyError::InvalidServerStatus { found, .. } => found.starts_with('5'),
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    // transient failures in a row
+    failures: u32,
+    opened_at: Option<Instant>,
+    // a single attempt is let through once open_for has passed,
+    // another one if it did not report back within open_for either
+    probe_at: Option<Instant>,
+}
+
+/// Fails connection attempts fast once the server looks down, after `open_for` one attempt
+/// probes it and closes the breaker again if it works
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// transient failures in a row which open the breaker, 0 turns it off
+    pub failure_threshold: u32,
+    pub open_for: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_for,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                opened_at: None,
+                probe_at: None,
+            }),
+        }
+    }
+
+    /// whether an attempt may be made now
+    pub fn check(&self) -> ProxyResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let opened_at = match state.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Ok(()),
+        };
+        let elapsed = opened_at.elapsed();
+        let probe_pending = matches!(state.probe_at, Some(at) if at.elapsed() < self.open_for);
+        if elapsed >= self.open_for && !probe_pending {
+            info!("circuit breaker lets a probe through");
+            state.probe_at = Some(Instant::now());
+            return Ok(());
+        }
+        // while a probe is out the next one may go once it timed out
+        let retry_in = match state.probe_at {
+            Some(at) if probe_pending => self.open_for.saturating_sub(at.elapsed()),
+            _ => self.open_for.saturating_sub(elapsed),
+        };
+        Err(ProxyError::CircuitOpen { retry_in })
+    }
+
+    /// failing fast right now, without letting a probe through
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        matches!(state.opened_at, Some(at) if at.elapsed() < self.open_for)
+    }
+
+    /// the server answered, whatever it said
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            info!("circuit breaker closed, server is back");
+        }
+        state.failures = 0;
+        state.opened_at = None;
+        state.probe_at = None;
+    }
+
+    /// the attempt says nothing about the server, e.g. a bad certificate,
+    /// if it was the probe another one may go right away
+    pub fn inconclusive(&self) {
+        self.state.lock().unwrap().probe_at = None;
+    }
+
+    pub fn failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.probe_at.is_some() || state.failures >= self.failure_threshold {
+            if state.opened_at.is_none() {
+                info!(
+                    "circuit breaker opened after {} failures, fail fast for {:?}",
+                    state.failures, self.open_for
+                );
+            }
+            state.opened_at = Some(Instant::now());
+            state.probe_at = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        for attempt in 0..40 {
+            let ceiling = Duration::from_millis(100 << attempt.min(4)).min(policy.max_delay);
+            assert!(policy.backoff(attempt) <= ceiling);
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(100));
+        breaker.failure();
+        assert!(breaker.check().is_ok());
+        breaker.failure();
+        assert!(matches!(
+            breaker.check(),
+            Err(ProxyError::CircuitOpen { .. })
+        ));
+
+        // a failed probe opens it again
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        breaker.failure();
+        assert!(breaker.check().is_err());
+
+        // a working one closes it
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(breaker.check().is_ok());
+        breaker.success();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+
+        // callers behind a probe are told when it times out
+        breaker.failure();
+        breaker.failure();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(breaker.check().is_ok());
+        match breaker.check() {
+            Err(ProxyError::CircuitOpen { retry_in }) => assert!(retry_in > Duration::ZERO),
+            other => panic!("breaker not open, got {:?}", other),
+        }
+        // a probe which told nothing lets the next one through
+        breaker.inconclusive();
+        assert!(breaker.check().is_ok());
+    }
+}
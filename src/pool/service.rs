@@ -0,0 +1,99 @@
+use std::{
+    hash::Hash,
+    task::{Context, Poll},
+};
+
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::pool::{Acquired, GracefulClose, HealthCheck, Pool, Pooled};
+
+/// Puts a `Pool` in front of a `MakeConnection` service, e.g. in a `tower::ServiceBuilder`
+/// below timeout, retry or rate limit layers
+#[derive(Clone)]
+pub struct PoolLayer<K, T> {
+    pool: Pool<K, T>,
+    key: K,
+}
+
+impl<K, T> PoolLayer<K, T> {
+    /// connections made by the wrapped service are kept in `pool` under `key`
+    pub fn new(pool: Pool<K, T>, key: K) -> Self {
+        Self { pool, key }
+    }
+}
+
+impl<K: Eq + Hash + Clone, T, MT> Layer<MT> for PoolLayer<K, T> {
+    type Service = PoolService<K, T, MT>;
+
+    fn layer(&self, mt: MT) -> Self::Service {
+        PoolService {
+            pool: self.pool.clone(),
+            key: self.key.clone(),
+            mt,
+            acquired: None,
+        }
+    }
+}
+
+/// A `Service` handing out pooled connections of one key, made by `mt` when none is idle.
+/// It is not ready while the pool is at its size limit, once ready it holds an idle connection
+/// or a slot for the next call, dropping the service gives it back
+pub struct PoolService<K: Eq + Hash, T, MT> {
+    pool: Pool<K, T>,
+    key: K,
+    mt: MT,
+    acquired: Option<Acquired<K, T>>,
+}
+
+// a clone has to get ready on its own
+impl<K: Eq + Hash + Clone, T, MT: Clone> Clone for PoolService<K, T, MT> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            key: self.key.clone(),
+            mt: self.mt.clone(),
+            acquired: None,
+        }
+    }
+}
+
+impl<K, T, MT> Service<()> for PoolService<K, T, MT>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    T: HealthCheck + GracefulClose + Send + 'static,
+    MT: Service<(), Response = T> + Clone + Send + 'static,
+    MT::Error: Into<anyhow::Error> + Send,
+    MT::Future: Unpin + Send + 'static,
+{
+    type Response = Pooled<K, T>;
+
+    type Error = anyhow::Error;
+
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.acquired.is_none() {
+            self.acquired = Some(futures_util::ready!(self.pool.poll_acquire(&self.key, cx)));
+        }
+        match self.acquired {
+            // nothing to make
+            Some(Acquired::Idle(_)) => Poll::Ready(Ok(())),
+            _ => self.mt.poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    fn call(&mut self, _: ()) -> Self::Future {
+        let reservation = match self.acquired.take() {
+            Some(Acquired::Idle(pooled)) => return Box::pin(async move { Ok(pooled) }),
+            Some(Acquired::Slot(reservation)) => reservation,
+            None => panic!("PoolService::call without poll_ready"),
+        };
+        // the ready maker goes with the call, the clone waits for the next poll_ready
+        let mt = self.mt.clone();
+        let mt = std::mem::replace(&mut self.mt, mt);
+        let pool = self.pool.clone();
+        let key = self.key.clone();
+        Box::pin(async move { pool.make(key, mt, reservation).await })
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+// upper bounds of the latency buckets, everything slower lands in one more
+const LATENCY_BOUNDS: &[Duration] = &[
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Durations counted by bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    /// upper bound of each bucket but the last, which has none
+    pub bounds: &'static [Duration],
+    pub counts: Vec<u64>,
+    pub sum: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            bounds: LATENCY_BOUNDS,
+            counts: vec![0; LATENCY_BOUNDS.len() + 1],
+            sum: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.sum / count as u32),
+        }
+    }
+
+    /// upper bound of the bucket holding the `q` quantile, `None` without samples
+    /// or when it is in the last bucket
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return self.bounds.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// What the pool holds and how `get` was served, for sizing `max_idle` and friends
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolStats {
+    /// idle connections of all keys
+    pub idle: usize,
+    /// callers waiting for a connection or a slot because the pool is full
+    pub waiters: usize,
+    /// connections alive or being made
+    pub size: usize,
+    /// connections made, including prewarmed ones
+    pub created: u64,
+    /// `get` served by an idle connection
+    pub idle_hits: u64,
+    /// `get` served by a connection put back while it waited
+    pub waiter_hits: u64,
+    /// `get` served by a connection made for it
+    pub fresh: u64,
+    pub create_failures: u64,
+    pub create_latency: LatencyHistogram,
+}
+
+impl PoolStats {
+    /// share of `get` served without making a connection
+    pub fn reuse_ratio(&self) -> Option<f64> {
+        let hits = self.idle_hits + self.waiter_hits;
+        match hits + self.fresh {
+            0 => None,
+            total => Some(hits as f64 / total as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        for ms in [5, 20, 20, 30, 20000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.counts[0], 1);
+        assert_eq!(histogram.counts[1], 2);
+        assert_eq!(histogram.counts[LATENCY_BOUNDS.len()], 1);
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(4015)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(25)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.quantile(1.0), None);
+    }
+}
@@ -0,0 +1,682 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::StatusCode;
+use log::{error, info};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, PKCS_ECDSA_P256_SHA256};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::sign::{self, CertifiedKey};
+use serde_json::{json, Value};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+};
+use url::{Position, Url};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    server::certs::{CertStore, CERT_FILE, KEY_FILE},
+    transport::{
+        http1::{self, HttpConnection, Response},
+        tls::{self, ClientTlsOptions, ALPN_HTTP1},
+        ServerEndpoint,
+    },
+    util::load_private_key,
+};
+
+/// alpn of acme tls-alpn-01 validation handshakes (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+const ACCOUNT_KEY_FILE: &str = "account.pem";
+/// certificates are renewed once they expire within this long
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+/// orders and authorizations are polled this often, this many times
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+
+/// How the acme server checks we control the domains
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcmeChallenge {
+    /// tls-alpn-01 answered by the tls listener of the server itself
+    TlsAlpn01,
+    /// http-01 answered by a plain http listener on this address, usually port 80
+    Http01(String),
+}
+
+/// Certificate the server obtains and renews by itself
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// directory url, e.g. https://acme-v02.api.letsencrypt.org/directory
+    pub directory_url: String,
+    pub domains: Vec<String>,
+    /// e.g. mailto:admin@example.com
+    pub contact: Vec<String>,
+    /// holds the account key and the certificate, in the layout of a cert dir
+    pub cache_dir: PathBuf,
+    pub challenge: AcmeChallenge,
+    /// trust for the acme server, e.g. the ca of a local pebble
+    pub tls_options: ClientTlsOptions,
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn acme_error<E: std::fmt::Display>(e: E) -> ProxyError {
+    ProxyError::AcmeError(e.to_string())
+}
+
+fn json_str<'a>(value: &'a Value, key: &str) -> ProxyResult<&'a str> {
+    value[key]
+        .as_str()
+        .ok_or_else(|| ProxyError::AcmeError(format!("`{}` missing in {}", key, value)))
+}
+
+/// Account key and the jws requests signed by it (RFC 8555)
+struct AcmeClient {
+    tls_connector: tokio_rustls::TlsConnector,
+    conn: Option<HttpConnection>,
+    directory: Value,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    // account url, sent instead of the jwk once known
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(config: &AcmeConfig) -> ProxyResult<Self> {
+        let key_path = config.cache_dir.join(ACCOUNT_KEY_FILE);
+        if !key_path.exists() {
+            let key = rcgen::KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+            std::fs::create_dir_all(&config.cache_dir)?;
+            write_private(&key_path, key.serialize_pem().as_bytes())?;
+            info!("acme account key created at {:?}", key_path);
+        }
+        let key = load_private_key(key_path, None)?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.0)
+            .map_err(acme_error)?;
+        // uncompressed point, 0x04 || x || y
+        let point = key.public_key().as_ref();
+        let jwk = json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&point[1..33]),
+            "y": b64(&point[33..]),
+        });
+        let tls_config = tls::client_config(&[ALPN_HTTP1], &config.tls_options)?;
+        let mut client = Self {
+            tls_connector: tls::connector(tls_config),
+            conn: None,
+            directory: Value::Null,
+            key,
+            jwk,
+            kid: None,
+            nonce: None,
+        };
+        let res = client
+            .request("GET", &config.directory_url, &[], &[])
+            .await?;
+        client.directory = serde_json::from_slice(&res.body)?;
+        Ok(client)
+    }
+
+    /// RFC 7638 thumbprint, members in lexicographic order without whitespace
+    fn thumbprint(&self) -> String {
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            self.jwk["x"], self.jwk["y"]
+        );
+        b64(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+
+    /// flattened jws, no payload makes a POST-as-GET
+    fn sign(&self, url: &str, payload: Option<&Value>, nonce: &str) -> ProxyResult<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|p| b64(p.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(acme_error)?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        });
+        Ok(jws.to_string().into_bytes())
+    }
+
+    /// plain request, the connection is kept while the host stays the same
+    async fn request(
+        &mut self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> ProxyResult<Response> {
+        let url = Url::parse(url)?;
+        let endpoint = ServerEndpoint::new(&url[Position::BeforeHost..Position::AfterPort], "/")?;
+        let conn = match self.conn.as_mut() {
+            Some(conn) if conn.endpoint == endpoint => conn,
+            _ => self
+                .conn
+                .insert(HttpConnection::new(self.tls_connector.clone(), endpoint)),
+        };
+        let res = conn
+            .request(method, &url[Position::BeforePath..], headers, body)
+            .await?;
+        if let Some(nonce) = res.header("Replay-Nonce") {
+            self.nonce = Some(String::from_utf8_lossy(nonce).to_string());
+        }
+        Ok(res)
+    }
+
+    async fn nonce(&mut self) -> ProxyResult<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = json_str(&self.directory, "newNonce")?.to_string();
+        self.request("GET", &url, &[], &[]).await?;
+        self.nonce
+            .take()
+            .ok_or_else(|| ProxyError::AcmeError("no Replay-Nonce".to_string()))
+    }
+
+    /// signed POST, retried once when the server rejects the nonce
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> ProxyResult<Response> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, payload, &nonce)?;
+            let res = self
+                .request(
+                    "POST",
+                    url,
+                    &[("Content-Type", "application/jose+json")],
+                    &body,
+                )
+                .await?;
+            if res.status.is_success() {
+                return Ok(res);
+            }
+            let problem: Value = serde_json::from_slice(&res.body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(ProxyError::AcmeError(format!(
+                "{} answered {}, detail is {}",
+                url, res.status, problem
+            )));
+        }
+    }
+
+    async fn post_json(&mut self, url: &str, payload: Option<&Value>) -> ProxyResult<Value> {
+        let res = self.post(url, payload).await?;
+        Ok(serde_json::from_slice(&res.body)?)
+    }
+
+    /// POST-as-GET `url` until its status is `wanted`
+    async fn poll(&mut self, url: &str, wanted: &str) -> ProxyResult<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let value = self.post_json(url, None).await?;
+            match value["status"].as_str() {
+                Some(status) if status == wanted => return Ok(value),
+                Some("invalid") => {
+                    return Err(ProxyError::AcmeError(format!(
+                        "{} is invalid, detail is {}",
+                        url, value
+                    )))
+                }
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+        Err(ProxyError::AcmeError(format!(
+            "{} is not {} in time",
+            url, wanted
+        )))
+    }
+
+    async fn register(&mut self, contact: &[String]) -> ProxyResult<()> {
+        let url = json_str(&self.directory, "newAccount")?.to_string();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let res = self.post(&url, Some(&payload)).await?;
+        let kid = res
+            .header("Location")
+            .ok_or_else(|| ProxyError::AcmeError("no account Location".to_string()))?;
+        self.kid = Some(String::from_utf8_lossy(kid).to_string());
+        Ok(())
+    }
+}
+
+/// Obtains certificates for the configured domains and renews them before they expire
+pub struct Acme {
+    config: AcmeConfig,
+    cert_store: Arc<CertStore>,
+    // key authorizations served over http-01 by token
+    http_tokens: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Acme {
+    pub fn new(config: AcmeConfig, cert_store: Arc<CertStore>) -> Self {
+        Self {
+            config,
+            cert_store,
+            http_tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn cert_dir(&self) -> PathBuf {
+        self.config.cache_dir.join(&self.config.domains[0])
+    }
+
+    /// renew when needed, runs forever
+    pub async fn run(self) {
+        if let AcmeChallenge::Http01(addr) = &self.config.challenge {
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    info!("acme http-01 listen on {}", addr);
+                    tokio::spawn(serve_http01(listener, self.http_tokens.clone()));
+                }
+                Err(e) => error!("acme http-01 listen on {} failed, detail is {:?}", addr, e),
+            }
+        }
+        loop {
+            let interval = match self.renew_if_needed().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(e) => {
+                    error!("acme renewal failed, detail is {:?}", e);
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    async fn renew_if_needed(&self) -> ProxyResult<()> {
+        if let Some(expires_in) = self.expires_in() {
+            if expires_in > RENEW_BEFORE {
+                return Ok(());
+            }
+        }
+        info!("acme obtain certificate for {:?}", self.config.domains);
+        self.issue().await?;
+        self.cert_store.reload()?;
+        info!("acme certificate for {:?} installed", self.config.domains);
+        Ok(())
+    }
+
+    /// time left on the cached certificate, `None` without a usable one
+    fn expires_in(&self) -> Option<Duration> {
+        let pem = std::fs::read(self.cert_dir().join(CERT_FILE)).ok()?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).ok()?;
+        let (_, cert) = X509Certificate::from_der(&pem.contents).ok()?;
+        let not_after = cert.validity().not_after.timestamp();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        Some(Duration::from_secs(
+            not_after.saturating_sub(now).max(0) as u64
+        ))
+    }
+
+    async fn issue(&self) -> ProxyResult<()> {
+        let mut client = AcmeClient::new(&self.config).await?;
+        client.register(&self.config.contact).await?;
+
+        let url = json_str(&client.directory, "newOrder")?.to_string();
+        let identifiers: Vec<_> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let res = client
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = res
+            .header("Location")
+            .map(|l| String::from_utf8_lossy(l).to_string())
+            .ok_or_else(|| ProxyError::AcmeError("no order Location".to_string()))?;
+        let order: Value = serde_json::from_slice(&res.body)?;
+        let authorizations: Vec<String> = order["authorizations"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|u| u.as_str())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        for url in authorizations {
+            self.authorize(&mut client, &url).await?;
+        }
+        let order = client.poll(&order_url, "ready").await?;
+
+        let mut params = CertificateParams::new(self.config.domains.clone());
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params)?;
+        let csr = json!({ "csr": b64(&cert.serialize_request_der()?) });
+        client
+            .post(json_str(&order, "finalize")?, Some(&csr))
+            .await?;
+        let order = client.poll(&order_url, "valid").await?;
+        let chain = client.post(json_str(&order, "certificate")?, None).await?;
+
+        // written aside first, so the file watcher never reads half a file
+        let dir = self.cert_dir();
+        std::fs::create_dir_all(&dir)?;
+        write_private(
+            &dir.join("privkey.pem.new"),
+            cert.serialize_private_key_pem().as_bytes(),
+        )?;
+        std::fs::write(dir.join("fullchain.pem.new"), chain.body)?;
+        std::fs::rename(dir.join("privkey.pem.new"), dir.join(KEY_FILE))?;
+        std::fs::rename(dir.join("fullchain.pem.new"), dir.join(CERT_FILE))?;
+        Ok(())
+    }
+
+    async fn authorize(&self, client: &mut AcmeClient, url: &str) -> ProxyResult<()> {
+        let authz = client.post_json(url, None).await?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = json_str(&authz["identifier"], "value")?.to_string();
+        let kind = match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+            AcmeChallenge::Http01(_) => "http-01",
+        };
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == kind))
+            .ok_or_else(|| {
+                ProxyError::AcmeError(format!("no {} challenge for {}", kind, domain))
+            })?;
+        let token = json_str(challenge, "token")?.to_string();
+        let key_authorization = client.key_authorization(&token);
+        match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = challenge_cert(&domain, &key_authorization)?;
+                self.cert_store.set_challenge(&domain, Some(cert));
+            }
+            AcmeChallenge::Http01(_) => {
+                let mut tokens = self.http_tokens.lock().unwrap();
+                tokens.insert(token.clone(), key_authorization);
+            }
+        }
+        info!("acme {} challenge for {} ready", kind, domain);
+
+        let result = match client
+            .post(json_str(challenge, "url")?, Some(&json!({})))
+            .await
+        {
+            Ok(_) => client.poll(url, "valid").await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        self.cert_store.set_challenge(&domain, None);
+        self.http_tokens.lock().unwrap().remove(&token);
+        result
+    }
+}
+
+/// self signed certificate carrying the key authorization digest in the acmeIdentifier extension
+/// keys are only readable by the owner, also when a leftover file is overwritten
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // the mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+}
+
+fn challenge_cert(domain: &str, key_authorization: &str) -> ProxyResult<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+    )];
+    let cert = rcgen::Certificate::from_params(params)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let signing_key = sign::any_supported_type(&key).or(Err(ProxyError::InvalidPrivateKey))?;
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(cert.serialize_der()?)],
+        Arc::new(signing_key),
+    ))
+}
+
+async fn serve_http01(listener: TcpListener, tokens: Arc<Mutex<HashMap<String, String>>>) {
+    while let Ok((inbound, _)) = listener.accept().await {
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond_http01(inbound, tokens).await {
+                error!("acme http-01 request failed, detail is {:?}", e);
+            }
+        });
+    }
+}
+
+async fn respond_http01(
+    inbound: TcpStream,
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+) -> ProxyResult<()> {
+    let mut inbound = BufReader::new(inbound);
+    let head = match http1::read_request_head(&mut inbound).await? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    let key_authorization = head
+        .path
+        .strip_prefix(HTTP_CHALLENGE_PREFIX)
+        .and_then(|token| tokens.lock().unwrap().get(token).cloned());
+    match key_authorization {
+        Some(key_authorization) => {
+            info!("acme http-01 validation of {}", head.path);
+            http1::write_response(
+                inbound.get_mut(),
+                StatusCode::OK,
+                key_authorization.as_bytes(),
+            )
+            .await
+        }
+        None => http1::write_response(inbound.get_mut(), StatusCode::NOT_FOUND, &[]).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{server::make_acceptor, transport::tls::ServerTlsOptions};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+    use rustls::{
+        internal::msgs::handshake::DigitallySignedStruct, HandshakeSignatureValid, RootCertStore,
+        ServerCertVerified, ServerCertVerifier, TLSError,
+    };
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::webpki::DNSNameRef;
+
+    /// webpki refuses the critical acmeIdentifier extension even for the handshake signature,
+    /// validators do not, so the test client checks nothing
+    struct AcceptAnything;
+
+    impl ServerCertVerifier for AcceptAnything {
+        fn verify_server_cert(
+            &self,
+            _: &RootCertStore,
+            _: &[rustls::Certificate],
+            _: DNSNameRef,
+            _: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &rustls::Certificate,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, TLSError> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acme_challenges() {
+        // jws signatures verify with the jwk
+        let key = rcgen::KeyPair::generate(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der())
+            .unwrap();
+        let point = key.public_key().as_ref().to_vec();
+        let client = AcmeClient {
+            tls_connector: tls::connector(rustls::ClientConfig::new()),
+            conn: None,
+            directory: Value::Null,
+            key,
+            jwk: json!({ "x": b64(&point[1..33]), "y": b64(&point[33..]) }),
+            kid: None,
+            nonce: None,
+        };
+        let jws: Value =
+            serde_json::from_slice(&client.sign("https://acme/new", None, "nonce").unwrap())
+                .unwrap();
+        let message = format!("{}.", jws["protected"].as_str().unwrap());
+        let signature =
+            base64::decode_config(jws["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+            .verify(message.as_bytes(), &signature)
+            .unwrap();
+        let key_authorization = client.key_authorization("token");
+        assert_eq!(key_authorization.len(), "token.".len() + 43);
+
+        // http-01 answers known tokens only
+        let tokens = Arc::new(Mutex::new(HashMap::new()));
+        tokens
+            .lock()
+            .unwrap()
+            .insert("token".to_string(), key_authorization.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_http01(listener, tokens));
+        for (token, status, body) in [
+            ("token", StatusCode::OK, key_authorization.as_bytes()),
+            ("other", StatusCode::NOT_FOUND, &b""[..]),
+        ] {
+            let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            let path = format!("{}{}", HTTP_CHALLENGE_PREFIX, token);
+            let res = http1::request(&mut stream, "GET", &path, "acme.test", &[], &[])
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(res.status, status);
+            assert_eq!(res.body, body);
+            stream.get_mut().shutdown().await.unwrap();
+        }
+
+        // tls-alpn-01 handshakes get the challenge certificate, others the normal one
+        let cert = rcgen::generate_simple_self_signed(vec!["acme.test".to_string()]).unwrap();
+        let cert_store = Arc::new(
+            CertStore::new(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap(),
+        );
+        cert_store.set_challenge(
+            "acme.test",
+            Some(challenge_cert("acme.test", &key_authorization).unwrap()),
+        );
+        let spawn_acceptor = |acme_tls_alpn| {
+            let acceptor = make_acceptor(
+                cert_store.clone(),
+                &ServerTlsOptions::default(),
+                acme_tls_alpn,
+            )
+            .unwrap();
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::spawn(async move {
+                    while let Ok((inbound, _)) = listener.accept().await {
+                        let _ = acceptor.accept(inbound).await;
+                    }
+                });
+                let mut endpoint = ServerEndpoint::new(&addr.to_string(), "/").unwrap();
+                endpoint.sni = "acme.test".to_string();
+                endpoint
+            }
+        };
+        let connector = |alpn: &[u8]| {
+            let mut config = rustls::ClientConfig::new();
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAnything));
+            config.alpn_protocols = vec![alpn.to_vec()];
+            tls::connector(config)
+        };
+
+        // without tls-alpn-01 the server does not offer its alpn
+        let endpoint = spawn_acceptor(false).await;
+        let stream = tls::connect(&connector(ACME_TLS_ALPN), &endpoint).await;
+        assert!(stream
+            .map(|s| rustls::Session::get_alpn_protocol(s.get_ref().1).is_none())
+            .unwrap_or(true));
+
+        let endpoint = spawn_acceptor(true).await;
+        for alpn in [ACME_TLS_ALPN, ALPN_HTTP1] {
+            let stream = tls::connect(&connector(alpn), &endpoint).await.unwrap();
+            let session = stream.get_ref().1;
+            assert_eq!(rustls::Session::get_alpn_protocol(session), Some(alpn));
+            let certs = rustls::Session::get_peer_certificates(session).unwrap();
+            let (_, cert) = X509Certificate::from_der(&certs[0].0).unwrap();
+            let acme_identifier = cert
+                .extensions()
+                .iter()
+                .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+                .map(|e| e.value.to_vec());
+            let expected = digest(&SHA256, key_authorization.as_bytes());
+            assert_eq!(
+                acme_identifier.map(|v| v.ends_with(expected.as_ref())),
+                if alpn == ACME_TLS_ALPN {
+                    Some(true)
+                } else {
+                    None
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::{error, info};
+use quinn::rustls as quic_rustls;
+use rustls::sign::{self, CertifiedKey};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    server::acme::ACME_TLS_ALPN,
+    transport::quic::into_der,
+    util::{load_certs, load_private_key},
+};
+
+/// certificate files are checked for changes this often
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// file names inside each per-domain directory, as laid out by certbot
+pub(crate) const CERT_FILE: &str = "fullchain.pem";
+pub(crate) const KEY_FILE: &str = "privkey.pem";
+
+/// One certificate chain with its key, ready for both tls stacks
+#[derive(Clone)]
+struct Entry {
+    tcp: CertifiedKey,
+    quic: Arc<quic_rustls::sign::CertifiedKey>,
+}
+
+impl Entry {
+    fn new(certs: Vec<rustls::Certificate>, key: rustls::PrivateKey) -> ProxyResult<Self> {
+        let signing_key = sign::any_supported_type(&key).or(Err(ProxyError::InvalidPrivateKey))?;
+        let tcp = CertifiedKey::new(certs.clone(), Arc::new(signing_key));
+        let (certs, key) = into_der(certs, key)?;
+        let signing_key = quic_rustls::crypto::ring::sign::any_supported_type(&key)?;
+        let quic = Arc::new(quic_rustls::sign::CertifiedKey::new(certs, signing_key));
+        Ok(Self { tcp, quic })
+    }
+
+    fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&str>,
+    ) -> ProxyResult<(Self, Vec<String>)> {
+        let certs = load_certs(cert_path.to_path_buf())?;
+        let names = certs.first().map(|c| dns_names(&c.0)).unwrap_or_default();
+        let key = load_private_key(key_path.to_path_buf(), passphrase)?;
+        Ok((Self::new(certs, key)?, names))
+    }
+}
+
+/// dns names of a certificate's subject alternative name extension
+fn dns_names(cert: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok((_, cert)) = X509Certificate::from_der(cert) {
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                if let GeneralName::DNSName(name) = name {
+                    names.push(name.to_ascii_lowercase());
+                }
+            }
+        }
+    }
+    names
+}
+
+struct Certs {
+    default: Option<Entry>,
+    by_name: HashMap<String, Entry>,
+}
+
+impl Certs {
+    /// exact name first, then a wildcard covering its first label, else the default
+    fn get(&self, server_name: Option<&str>) -> Option<&Entry> {
+        let name = match server_name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return self.default.as_ref(),
+        };
+        if let Some(entry) = self.by_name.get(&name) {
+            return Some(entry);
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| self.by_name.get(&format!("*.{}", parent)))
+            .or(self.default.as_ref())
+    }
+}
+
+/// Files the certificates come from, absent for a fixed certificate
+struct Sources {
+    // served when no sni matches, none when only acme certificates are used
+    default: Option<(PathBuf, PathBuf)>,
+    // each holds one sub directory per domain with `fullchain.pem` and `privkey.pem`
+    dirs: Vec<PathBuf>,
+    // for encrypted keys
+    key_passphrase: Option<String>,
+}
+
+impl Sources {
+    fn load(&self) -> ProxyResult<Certs> {
+        let default = match &self.default {
+            Some((cert_path, key_path)) => {
+                Some(Entry::load(cert_path, key_path, self.key_passphrase.as_deref())?.0)
+            }
+            None => None,
+        };
+        let mut by_name = HashMap::new();
+        for (cert_path, key_path) in self.domain_files()? {
+            let (entry, names) =
+                Entry::load(&cert_path, &key_path, self.key_passphrase.as_deref())?;
+            if names.is_empty() {
+                info!("{:?} has no dns names, skip it", cert_path);
+            }
+            for name in names {
+                by_name.insert(name, entry.clone());
+            }
+        }
+        Ok(Certs { default, by_name })
+    }
+
+    fn domain_files(&self) -> ProxyResult<Vec<(PathBuf, PathBuf)>> {
+        let mut files = Vec::new();
+        for dir in &self.dirs {
+            if !dir.exists() {
+                continue;
+            }
+            let mut dir_files = Vec::new();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let (cert_path, key_path) = (path.join(CERT_FILE), path.join(KEY_FILE));
+                if cert_path.exists() && key_path.exists() {
+                    dir_files.push((cert_path, key_path));
+                }
+            }
+            dir_files.sort();
+            files.extend(dir_files);
+        }
+        Ok(files)
+    }
+
+    /// modification times of every file in use, any difference triggers a reload
+    fn modified(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let mut paths = Vec::new();
+        if let Some((cert_path, key_path)) = &self.default {
+            paths.push(cert_path.clone());
+            paths.push(key_path.clone());
+        }
+        for (cert_path, key_path) in self.domain_files().unwrap_or_default() {
+            paths.push(cert_path);
+            paths.push(key_path);
+        }
+        paths
+            .into_iter()
+            .map(|path| {
+                // metadata follows symlinks, so a renewed certbot `live` link counts as changed
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    }
+}
+
+/// Server certificates picked by sni, reloaded from disk without touching established connections
+pub struct CertStore {
+    sources: Option<Sources>,
+    certs: RwLock<Certs>,
+    // acme tls-alpn-01 validation certificates by domain
+    challenges: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertStore")
+            .field("dirs", &self.sources.as_ref().map(|s| &s.dirs))
+            .finish()
+    }
+}
+
+impl CertStore {
+    /// always serve `certs`
+    pub fn new(certs: Vec<rustls::Certificate>, key: rustls::PrivateKey) -> ProxyResult<Self> {
+        Ok(Self {
+            sources: None,
+            certs: RwLock::new(Certs {
+                default: Some(Entry::new(certs, key)?),
+                by_name: HashMap::new(),
+            }),
+            challenges: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// serve the certificates found in `dirs` by sni, `default` (cert and key path) otherwise,
+    /// `key_passphrase` decrypts any encrypted key
+    pub fn load(
+        default: Option<(PathBuf, PathBuf)>,
+        dirs: Vec<PathBuf>,
+        key_passphrase: Option<String>,
+    ) -> ProxyResult<Self> {
+        let sources = Sources {
+            default,
+            dirs,
+            key_passphrase,
+        };
+        let certs = sources.load()?;
+        info!("loaded certificates for {:?}", certs.by_name.keys());
+        Ok(Self {
+            sources: Some(sources),
+            certs: RwLock::new(certs),
+            challenges: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// read every certificate again, the old ones stay in use if anything fails
+    pub fn reload(&self) -> ProxyResult<()> {
+        if let Some(sources) = &self.sources {
+            let certs = sources.load()?;
+            info!("reloaded certificates for {:?}", certs.by_name.keys());
+            *self.certs.write().unwrap() = certs;
+        }
+        Ok(())
+    }
+
+    fn get(&self, server_name: Option<&str>) -> Option<Entry> {
+        self.certs.read().unwrap().get(server_name).cloned()
+    }
+
+    /// serve `cert` to acme tls-alpn-01 validation requests for `domain`, `None` removes it
+    pub fn set_challenge(&self, domain: &str, cert: Option<CertifiedKey>) {
+        let mut challenges = self.challenges.write().unwrap();
+        match cert {
+            Some(cert) => challenges.insert(domain.to_ascii_lowercase(), cert),
+            None => challenges.remove(&domain.to_ascii_lowercase()),
+        };
+    }
+
+    /// reload when a file changes or on SIGHUP, runs forever
+    pub async fn watch(self: Arc<Self>) {
+        let sources = match &self.sources {
+            Some(sources) => sources,
+            None => return,
+        };
+        let mut hangup = hangup_signal();
+        let mut modified = sources.modified();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => {
+                    let now = sources.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("certificate files changed");
+                }
+                _ = recv_hangup(&mut hangup) => {
+                    info!("get SIGHUP");
+                    modified = sources.modified();
+                }
+            }
+            if let Err(e) = self.reload() {
+                error!(
+                    "reload certificates failed, keep the old ones, detail is {:?}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+type HangupSignal = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type HangupSignal = Option<()>;
+
+#[cfg(unix)]
+fn hangup_signal() -> HangupSignal {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup()).ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> HangupSignal {
+    None
+}
+
+/// wait for the next SIGHUP, never resolves without one
+async fn recv_hangup(hangup: &mut HangupSignal) {
+    #[cfg(unix)]
+    if let Some(signal) = hangup {
+        if signal.recv().await.is_some() {
+            return;
+        }
+    }
+    let _ = hangup;
+    futures::future::pending::<()>().await
+}
+
+impl rustls::ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: rustls::ClientHello) -> Option<CertifiedKey> {
+        let server_name: Option<&str> = client_hello.server_name().map(|n| n.into());
+        let acme = client_hello
+            .alpn()
+            .map(|alpn| alpn.contains(&ACME_TLS_ALPN))
+            .unwrap_or(false);
+        if acme {
+            let challenges = self.challenges.read().unwrap();
+            return challenges.get(&server_name?.to_ascii_lowercase()).cloned();
+        }
+        self.get(server_name).map(|entry| entry.tcp)
+    }
+}
+
+impl quic_rustls::server::ResolvesServerCert for CertStore {
+    fn resolve(
+        &self,
+        client_hello: quic_rustls::server::ClientHello<'_>,
+    ) -> Option<Arc<quic_rustls::sign::CertifiedKey>> {
+        self.get(client_hello.server_name()).map(|entry| entry.quic)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_cert(dir: &Path, names: &[&str]) {
+        let cert = rcgen::generate_simple_self_signed(
+            names.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(CERT_FILE), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join(KEY_FILE), cert.serialize_private_key_pem()).unwrap();
+    }
+
+    #[test]
+    fn test_cert_store_sni_and_reload() {
+        let dir = std::env::temp_dir().join(format!("ss-certs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        write_cert(&dir.join("default"), &["default.example"]);
+        write_cert(&dir.join("live").join("a"), &["a.example", "*.b.example"]);
+
+        let store = CertStore::load(
+            Some((
+                dir.join("default").join(CERT_FILE),
+                dir.join("default").join(KEY_FILE),
+            )),
+            vec![dir.join("live")],
+            None,
+        )
+        .unwrap();
+        let names_of = |name: Option<&str>| dns_names(&store.get(name).unwrap().tcp.cert[0].0);
+        assert_eq!(
+            names_of(Some("A.example")),
+            vec!["a.example", "*.b.example"]
+        );
+        assert_eq!(
+            names_of(Some("x.b.example")),
+            vec!["a.example", "*.b.example"]
+        );
+        assert_eq!(names_of(Some("x.y.b.example")), vec!["default.example"]);
+        assert_eq!(names_of(None), vec!["default.example"]);
+
+        // a new domain shows up after reload, a broken one keeps the old set
+        write_cert(&dir.join("live").join("c"), &["c.example"]);
+        store.reload().unwrap();
+        assert_eq!(names_of(Some("c.example")), vec!["c.example"]);
+        std::fs::write(dir.join("live").join("c").join(KEY_FILE), "broken").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(names_of(Some("c.example")), vec!["c.example"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
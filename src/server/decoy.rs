@@ -0,0 +1,264 @@
+use std::{path::PathBuf, str::FromStr};
+
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream};
+use http::{Method, StatusCode};
+use log::info;
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    transport::http1::{self, RequestHead, MAX_BODY_LEN},
+};
+
+const INDEX_FILE: &str = "index.html";
+const BUILTIN_INDEX: &str = "<!DOCTYPE html>
+<html>
+<head><title>Welcome</title></head>
+<body>
+<h1>It works!</h1>
+<p>This site is under construction, please check back later.</p>
+</body>
+</html>
+";
+const NOT_FOUND_PAGE: &str = "<html>
+<head><title>404 Not Found</title></head>
+<body>
+<center><h1>404 Not Found</h1></center>
+</body>
+</html>
+";
+// hop-by-hop headers are not forwarded between h2 and http/1.1
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+    "host",
+    "content-length",
+];
+
+/// What answers requests which are not tunnels, so active probes see an ordinary website
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoy {
+    /// reverse proxy to a plain http server at `host:port`
+    Upstream(String),
+    /// files below a directory, `index.html` for directories
+    Static(PathBuf),
+    /// a built-in placeholder page
+    Builtin,
+}
+
+/// `builtin`, `http://host:port` or a directory
+impl FromStr for Decoy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "builtin" {
+            return Ok(Decoy::Builtin);
+        }
+        if let Some(addr) = s.strip_prefix("http://") {
+            let addr = addr.trim_end_matches('/');
+            if addr.is_empty() || addr.contains('/') {
+                return Err(format!("decoy upstream `{}` should be http://host:port", s));
+            }
+            return Ok(Decoy::Upstream(addr.to_string()));
+        }
+        Ok(Decoy::Static(PathBuf::from(s)))
+    }
+}
+
+impl Decoy {
+    /// answer `head` and whatever follows on the connection,
+    /// `body` is the request body if it has been read already
+    pub async fn serve_http1<T>(
+        &self,
+        mut inbound: BufReader<T>,
+        mut head: RequestHead,
+        mut body: Option<Vec<u8>>,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Decoy::Upstream(addr) = self {
+            info!("pass {} {} to decoy {}", head.method, head.path, addr);
+            let mut upstream = TcpStream::connect(addr.as_str()).await?;
+            upstream.write_all(&head.raw).await?;
+            match body {
+                // read already, so it is sent again as a single chunk
+                Some(body) if head.is_chunked() => {
+                    if !body.is_empty() {
+                        let size = format!("{:x}\r\n", body.len());
+                        upstream.write_all(size.as_bytes()).await?;
+                        upstream.write_all(&body).await?;
+                        upstream.write_all(b"\r\n").await?;
+                    }
+                    upstream.write_all(b"0\r\n\r\n").await?;
+                }
+                Some(body) => upstream.write_all(&body).await?,
+                None => {}
+            }
+            let _ = copy_bidirectional(&mut inbound, &mut upstream).await;
+            return Ok(());
+        }
+        loop {
+            // chunked ones too, so what follows is parsed as the next request
+            if body.is_none() {
+                http1::read_request_body(&mut inbound, &head).await?;
+            }
+            info!("decoy page for {} {}", head.method, head.path);
+            let (status, content_type, page) = self.page(&head.method, &head.path).await;
+            let res = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default(),
+                content_type,
+                page.len()
+            );
+            let inner = inbound.get_mut();
+            inner.write_all(res.as_bytes()).await?;
+            if head.method != "HEAD" {
+                inner.write_all(&page).await?;
+            }
+            inner.flush().await?;
+            head = match http1::read_request_head(&mut inbound).await? {
+                Some(head) => head,
+                None => return Ok(()),
+            };
+            body = None;
+        }
+    }
+
+    /// answer one request of an h2 connection
+    pub async fn respond_h2(
+        &self,
+        request: http::Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> ProxyResult<()> {
+        let (parts, mut recv) = request.into_parts();
+        let mut body = Vec::new();
+        while let Some(data) = recv.data().await {
+            let data = data?;
+            let _ = recv.flow_control().release_capacity(data.len());
+            if body.len() + data.len() > MAX_BODY_LEN {
+                return Err(ProxyError::InvalidHttpMessage(
+                    "decoy request body is too large".to_string(),
+                ));
+            }
+            body.extend_from_slice(&data);
+        }
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let (res, page) = match self {
+            Decoy::Upstream(addr) => {
+                info!("pass h2 {} {} to decoy {}", parts.method, path, addr);
+                let headers: Vec<(&str, &str)> = parts
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+                    .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+                    .chain(std::iter::once(("Connection", "close")))
+                    .collect();
+                let host = parts.uri.authority().map(|a| a.as_str()).unwrap_or(addr);
+                let mut upstream = BufReader::new(TcpStream::connect(addr.as_str()).await?);
+                let upstream_res = http1::request(
+                    &mut upstream,
+                    parts.method.as_str(),
+                    path,
+                    host,
+                    &headers,
+                    &body,
+                )
+                .await?
+                .ok_or_else(|| ProxyError::InvalidHttpMessage("decoy closed".to_string()))?;
+                let mut res = http::Response::builder().status(upstream_res.status);
+                for (name, value) in &upstream_res.headers {
+                    if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+                        res = res.header(name.as_str(), value.as_slice());
+                    }
+                }
+                (res, upstream_res.body)
+            }
+            _ => {
+                info!("decoy page for h2 {} {}", parts.method, path);
+                let (status, content_type, page) = self.page(parts.method.as_str(), path).await;
+                let res = http::Response::builder()
+                    .status(status)
+                    .header("Content-Type", content_type);
+                (res, page)
+            }
+        };
+        let res = res.header("Content-Length", page.len()).body(())?;
+        let head_only = parts.method == Method::HEAD || page.is_empty();
+        let mut send = respond.send_response(res, head_only)?;
+        if !head_only {
+            send.send_data(Bytes::from(page), true)?;
+        }
+        Ok(())
+    }
+
+    // status, content type and body of the static or built-in page at `path`
+    async fn page(&self, method: &str, path: &str) -> (StatusCode, &'static str, Vec<u8>) {
+        if method != "GET" && method != "HEAD" {
+            let status = StatusCode::METHOD_NOT_ALLOWED;
+            return (status, "text/plain", status.to_string().into_bytes());
+        }
+        let path = path.split('?').next().unwrap_or_default();
+        let found = match self {
+            Decoy::Static(dir) => read_file(dir, path).await,
+            _ if path == "/" || path == "/index.html" => {
+                Some((content_type(INDEX_FILE), BUILTIN_INDEX.as_bytes().to_vec()))
+            }
+            _ => None,
+        };
+        match found {
+            Some((content_type, page)) => (StatusCode::OK, content_type, page),
+            None => (
+                StatusCode::NOT_FOUND,
+                "text/html",
+                NOT_FOUND_PAGE.as_bytes().to_vec(),
+            ),
+        }
+    }
+}
+
+// file at `path` below `dir`, paths leaving it are not found
+async fn read_file(dir: &std::path::Path, path: &str) -> Option<(&'static str, Vec<u8>)> {
+    let mut file = dir.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        if segment == ".." || segment.contains('\\') {
+            return None;
+        }
+        file.push(segment);
+    }
+    if tokio::fs::metadata(&file).await.ok()?.is_dir() {
+        file.push(INDEX_FILE);
+    }
+    let page = tokio::fs::read(&file).await.ok()?;
+    let name = file.file_name()?.to_str()?;
+    Some((content_type(name), page))
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().unwrap_or_default() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use log::info;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use crate::{
+    codec::proxy_protocol::{ProxyHeader, ProxyProtocolVersion},
+    error::ProxyResult,
+    transport::http1::RequestHead,
+};
+
+/// An address or a network like `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if net[..bytes] != addr[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ addr[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is neither an ip address nor a network", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// The tcp peer of a connection, the proxies trusted to tell who is behind them
+/// and how destinations learn about the client
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub trusted_proxies: Arc<Vec<IpRange>>,
+    /// announce the client to destinations with a PROXY protocol header
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, trusted_proxies: Arc<Vec<IpRange>>) -> Self {
+        Self {
+            addr,
+            trusted_proxies,
+            send_proxy_protocol: None,
+        }
+    }
+
+    /// connect to the destination of `client`, sending the PROXY header first if configured
+    pub async fn connect(&self, client: IpAddr, addrs: &[SocketAddr]) -> ProxyResult<TcpStream> {
+        let mut outbound = TcpStream::connect(addrs).await?;
+        if let Some(version) = self.send_proxy_protocol {
+            // the port is only known for the peer itself
+            let port = if client == self.addr.ip().to_canonical() {
+                self.addr.port()
+            } else {
+                0
+            };
+            let header = ProxyHeader::new(SocketAddr::new(client, port), outbound.peer_addr()?);
+            info!("send {:?} proxy header {:?}", version, header);
+            outbound.write_all(&header.encode(version)).await?;
+        }
+        Ok(outbound)
+    }
+
+    fn is_trusted(&self, addr: &IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|range| range.contains(addr))
+    }
+
+    /// address of the client behind `head`, taken from `X-Forwarded-For` or `X-Real-IP`
+    /// only when the peer is a trusted proxy, the peer itself otherwise
+    pub fn client_addr(&self, head: &RequestHead) -> IpAddr {
+        let peer = self.addr.ip().to_canonical();
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let parse = |value: &[u8]| -> Option<Vec<IpAddr>> {
+            std::str::from_utf8(value)
+                .ok()?
+                .split(',')
+                .map(|addr| parse_addr(addr.trim()))
+                .collect()
+        };
+        if let Some(chain) = head.header("X-Forwarded-For").and_then(parse) {
+            // every proxy appends its peer, the first untrusted one from the right is the client
+            if let Some(addr) = chain.iter().rev().find(|addr| !self.is_trusted(addr)) {
+                return *addr;
+            }
+            if let Some(addr) = chain.first() {
+                return *addr;
+            }
+        }
+        head.header("X-Real-IP")
+            .and_then(parse)
+            .and_then(|chain| chain.first().copied())
+            .unwrap_or(peer)
+    }
+}
+
+// plain addresses, or with a port as some proxies send them
+fn parse_addr(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|addr| addr.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn head(headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            raw: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let range: IpRange = "10.0.0.0/9".parse().unwrap();
+        assert!(range.contains(&"10.127.0.1".parse().unwrap()));
+        assert!(!range.contains(&"10.128.0.1".parse().unwrap()));
+        assert!(range.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+
+        let trusted = Arc::new(vec![range, "::1".parse().unwrap()]);
+        let proxy = Peer::new("10.0.0.1:4000".parse().unwrap(), trusted.clone());
+        let stranger = Peer::new("192.0.2.1:4000".parse().unwrap(), trusted.clone());
+        let forwarded = head(&[("X-Forwarded-For", "198.51.100.7, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(
+            proxy.client_addr(&forwarded),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+        // anyone else could make the headers up
+        assert_eq!(
+            stranger.client_addr(&forwarded),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        let real_ip = head(&[("X-Real-IP", "198.51.100.7:5555")]);
+        assert_eq!(
+            proxy.client_addr(&real_ip),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        let garbage = head(&[("X-Forwarded-For", "unknown")]);
+        assert_eq!(
+            proxy.client_addr(&garbage),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
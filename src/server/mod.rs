@@ -1,33 +1,98 @@
-use std::{convert::TryInto, net::SocketAddr, path::PathBuf, sync::Arc};
+pub mod acme;
+pub mod certs;
+pub mod decoy;
+pub mod forwarded;
+pub mod route;
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use crate::{
-    codec::Packet,
+    codec::{
+        proxy_protocol::{self, ProxyProtocolVersion},
+        Packet,
+    },
     error::{ProxyError, ProxyResult},
-    transport::WebSocketConnection,
-    util::{load_certs, load_private_key},
+    transport::{
+        h2::{H2Stream, PROTOCOL_WEBSOCKET},
+        http1::{self, PrefixedStream, RequestHead},
+        polling::{self, PollingSessions},
+        quic::{
+            self, decode_datagram, encode_datagram, make_server_endpoint, QuicHello, QuicStream,
+            MAX_HELLO_LEN,
+        },
+        tls::{self, ServerTlsOptions, ALPN_H2},
+        TransportType, WebSocketConnection,
+    },
 };
+use acme::{Acme, AcmeChallenge, AcmeConfig, ACME_TLS_ALPN};
+use certs::CertStore;
+use decoy::Decoy;
+use forwarded::{IpRange, Peer};
 use futures::{FutureExt, StreamExt};
+use route::{Route, Router};
 
+use h2::ext::Protocol;
+use http::{Method, StatusCode};
 use log::{error, info};
-use rustls::NoClientAuth;
+use quinn::{Connection, Endpoint, Incoming};
+use rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, ResolvesServerCert, RootCertStore, Session,
+};
 
 use tokio::{
-    io::copy_bidirectional,
-    net::{TcpListener, TcpStream},
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+/// udp sockets opened for quic datagrams are closed after this long without a reply
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct Server {
     listen_addr: String,
-    acceptor: TlsAcceptor,
-    authorization: Arc<String>,
+    // none when a reverse proxy in front terminates tls
+    acceptor: Option<TlsAcceptor>,
+    // websocket paths accepted over tcp, and routes of quic connections
+    router: Arc<Router>,
+    // certificates of both the tcp and the quic listener
+    cert_store: Option<Arc<CertStore>>,
+    // also listen for quic on the udp port
+    quic: bool,
+    // versions, cipher suites, alpn, resumption and client certificates
+    tls_options: ServerTlsOptions,
+    // obtain and renew certificates by acme
+    acme: Option<AcmeConfig>,
+    // answers whatever is not a tunnel
+    decoy: Option<Arc<Decoy>>,
+    // peers whose X-Forwarded-For and X-Real-IP are believed
+    trusted_proxies: Arc<Vec<IpRange>>,
+    // connections start with a PROXY protocol header of the load balancer
+    accept_proxy_protocol: bool,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         listen_addr: String,
         cert_pem_path: String,
         cert_key_path: String,
         authorization: String,
+        transport: TransportType,
+        routes: Vec<Route>,
+        tls_options: ServerTlsOptions,
+        cert_dir: Option<String>,
+        key_passphrase: Option<String>,
+        acme: Option<AcmeConfig>,
+        decoy: Option<Decoy>,
     ) -> ProxyResult<Self> {
         if listen_addr.is_empty()
             || cert_pem_path.is_empty()
@@ -36,66 +101,494 @@ impl Server {
         {
             return Err(ProxyError::EmptyParams);
         }
-        let abs_cert_path = std::fs::canonicalize(PathBuf::from(cert_pem_path.as_str()))?;
-        let abs_key_path = std::fs::canonicalize(PathBuf::from(cert_key_path.as_str()))?;
-        let certs = load_certs(abs_cert_path)?;
-        let key = load_private_key(abs_key_path)?;
+        // with acme the fixed certificate is optional
+        let default = if acme.is_some() && !PathBuf::from(cert_pem_path.as_str()).exists() {
+            info!(
+                "no certificate at {}, serve acme certificates only",
+                cert_pem_path
+            );
+            None
+        } else {
+            Some((
+                std::fs::canonicalize(PathBuf::from(cert_pem_path.as_str()))?,
+                std::fs::canonicalize(PathBuf::from(cert_key_path.as_str()))?,
+            ))
+        };
+        let mut dirs = Vec::new();
+        if let Some(dir) = cert_dir {
+            dirs.push(std::fs::canonicalize(PathBuf::from(dir))?);
+        }
+        if let Some(acme) = &acme {
+            if acme.domains.is_empty() {
+                return Err(ProxyError::EmptyParams);
+            }
+            dirs.push(acme.cache_dir.clone());
+        }
+        let cert_store = Arc::new(CertStore::load(default, dirs, key_passphrase)?);
+        let decoy = load_decoy(decoy)?;
+        let acme_tls_alpn = matches!(
+            &acme,
+            Some(AcmeConfig {
+                challenge: AcmeChallenge::TlsAlpn01,
+                ..
+            })
+        );
+        let acceptor = make_acceptor(cert_store.clone(), &tls_options, acme_tls_alpn)?;
+        Ok(Self {
+            listen_addr,
+            acceptor: Some(acceptor),
+            router: Arc::new(Router::new(routes, authorization)),
+            cert_store: Some(cert_store),
+            quic: transport == TransportType::Quic,
+            tls_options,
+            acme,
+            decoy,
+            trusted_proxies: Arc::new(Vec::new()),
+            accept_proxy_protocol: false,
+            send_proxy_protocol: None,
+        })
+    }
 
-        let mut server_config = rustls::ServerConfig::new(NoClientAuth::new());
-        server_config.set_single_cert(certs, key)?;
-        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    /// plain websocket server behind a reverse proxy which terminates tls,
+    /// client addresses are taken from the headers `trusted_proxies` set
+    pub fn plaintext(
+        listen_addr: String,
+        authorization: String,
+        routes: Vec<Route>,
+        trusted_proxies: Vec<IpRange>,
+        decoy: Option<Decoy>,
+    ) -> ProxyResult<Self> {
+        if listen_addr.is_empty() || authorization.is_empty() {
+            return Err(ProxyError::EmptyParams);
+        }
         Ok(Self {
             listen_addr,
-            acceptor,
-            authorization: Arc::new(authorization),
+            acceptor: None,
+            router: Arc::new(Router::new(routes, authorization)),
+            cert_store: None,
+            quic: false,
+            tls_options: ServerTlsOptions::default(),
+            acme: None,
+            decoy: load_decoy(decoy)?,
+            trusted_proxies: Arc::new(trusted_proxies),
+            accept_proxy_protocol: false,
+            send_proxy_protocol: None,
         })
     }
 
+    /// believe X-Forwarded-For and X-Real-IP sent by `trusted_proxies`, e.g. a cdn
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: Vec<IpRange>) {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+    }
+
+    /// `accept` requires a PROXY protocol header (v1 or v2) on every tcp connection,
+    /// `send` writes one to each destination
+    pub fn set_proxy_protocol(&mut self, accept: bool, send: Option<ProxyProtocolVersion>) {
+        self.accept_proxy_protocol = accept;
+        self.send_proxy_protocol = send;
+    }
+
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(cert_store) = &self.cert_store {
+            tokio::spawn(cert_store.clone().watch());
+            if let Some(acme) = self.acme.clone() {
+                tokio::spawn(Acme::new(acme, cert_store.clone()).run());
+            }
+
+            // quic listens on the udp port with the same number, websocket stays available on tcp
+            if self.quic {
+                let addr = tokio::net::lookup_host(self.listen_addr.as_str())
+                    .await?
+                    .next()
+                    .ok_or(ProxyError::EmptyParams)?;
+                let endpoint = make_server_endpoint(addr, cert_store.clone(), &self.tls_options)?;
+                info!("quic server listen on {}", addr);
+                tokio::spawn(serve_quic(
+                    endpoint,
+                    self.router.clone(),
+                    self.send_proxy_protocol,
+                ));
+            }
+        }
+
         // TODO: change to websocket server
         let listener = TcpListener::bind(self.listen_addr).await?;
-        while let Ok((inbound, _)) = listener.accept().await {
-            let serve =
-                serve(inbound, self.authorization.clone(), self.acceptor.clone()).map(|r| {
-                    if let Err(e) = r {
-                        error!("Failed to transfer; error={:?}", e);
-                    }
-                });
+        let sessions = PollingSessions::new();
+        while let Ok((inbound, peer)) = listener.accept().await {
+            let mut peer = Peer::new(peer, self.trusted_proxies.clone());
+            peer.send_proxy_protocol = self.send_proxy_protocol;
+            let serve = serve(
+                inbound,
+                peer,
+                self.accept_proxy_protocol,
+                self.router.clone(),
+                self.acceptor.clone(),
+                sessions.clone(),
+                self.decoy.clone(),
+            )
+            .map(|r| {
+                if let Err(e) = r {
+                    error!("Failed to transfer; error={:?}", e);
+                }
+            });
             tokio::spawn(serve);
         }
         Ok(())
     }
 }
 
+// static decoy sites are looked up relative to where the server starts
+fn load_decoy(decoy: Option<Decoy>) -> ProxyResult<Option<Arc<Decoy>>> {
+    let decoy = match decoy {
+        Some(Decoy::Static(dir)) => Decoy::Static(std::fs::canonicalize(dir)?),
+        Some(decoy) => decoy,
+        None => return Ok(None),
+    };
+    Ok(Some(Arc::new(decoy)))
+}
+
+/// `acme_tls_alpn` also offers the alpn of tls-alpn-01 validation handshakes
+fn make_acceptor(
+    cert_resolver: Arc<dyn ResolvesServerCert>,
+    options: &ServerTlsOptions,
+    acme_tls_alpn: bool,
+) -> ProxyResult<TlsAcceptor> {
+    let client_auth = if options.client_cas.is_empty() {
+        NoClientAuth::new()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for cert in &options.client_cas {
+            roots.add(cert).or(Err(ProxyError::InvalidCert))?;
+        }
+        AllowAnyAuthenticatedClient::new(roots)
+    };
+    let mut server_config = rustls::ServerConfig::new(client_auth);
+    server_config.cert_resolver = cert_resolver;
+    server_config.ciphersuites = tls::cipher_suites(&options.cipher_suites)?;
+    server_config.ignore_client_order = options.prefer_server_ciphers;
+    if !options.versions.is_empty() {
+        server_config.versions = options.versions.clone();
+    }
+    for version in &server_config.versions {
+        if !server_config
+            .ciphersuites
+            .iter()
+            .any(|suite| suite.usable_for_version(*version))
+        {
+            return Err(ProxyError::UnsupportedTlsOption(format!(
+                "no cipher suite for {:?}",
+                version
+            )));
+        }
+    }
+    if options.session_tickets {
+        server_config.ticketer = rustls::Ticketer::new();
+    }
+    server_config.session_storage = if options.session_cache_size == 0 {
+        Arc::new(rustls::NoServerSessionStorage {})
+    } else {
+        rustls::ServerSessionMemoryCache::new(options.session_cache_size)
+    };
+    // alpn decides between websocket over http/2 and over http/1.1,
+    // acme validation handshakes offer nothing but their own
+    let mut alpn = options.alpn.clone();
+    if acme_tls_alpn {
+        alpn.push(ACME_TLS_ALPN.to_vec());
+    }
+    server_config.set_protocols(&alpn);
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
 async fn serve(
-    inbound: TcpStream,
-    authorization: Arc<String>,
-    acceptor: TlsAcceptor,
+    mut inbound: TcpStream,
+    mut peer: Peer,
+    accept_proxy_protocol: bool,
+    router: Arc<Router>,
+    acceptor: Option<TlsAcceptor>,
+    sessions: PollingSessions,
+    decoy: Option<Arc<Decoy>>,
 ) -> ProxyResult<()> {
-    info!("get new connections");
+    if accept_proxy_protocol {
+        match proxy_protocol::read_header(&mut inbound).await? {
+            Some(header) => {
+                info!("{} is the load balancer of {}", peer.addr, header.source);
+                peer.addr = header.source;
+            }
+            None => {
+                info!("proxy header of {} carries no addresses", peer.addr);
+            }
+        }
+    }
+    info!("get new connections from {}", peer.addr);
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => {
+            return serve_http1(BufReader::new(inbound), peer, router, sessions, None, decoy).await
+        }
+    };
     // convert to tls stream
     let inbound = acceptor.accept(inbound).await?;
+    if inbound.get_ref().1.get_alpn_protocol() == Some(ACME_TLS_ALPN) {
+        info!("acme tls-alpn-01 validation handshake done");
+        return Ok(());
+    }
+    // only set when the server requires client certificates
+    let identity = inbound
+        .get_ref()
+        .1
+        .get_peer_certificates()
+        .and_then(|certs| tls::peer_identity(&certs.first()?.0));
+    if let Some(identity) = &identity {
+        info!("client certificate identity is {}", identity);
+    }
+    if inbound.get_ref().1.get_alpn_protocol() == Some(ALPN_H2) {
+        return serve_h2(inbound, peer, router, identity, decoy).await;
+    }
+    serve_http1(
+        BufReader::new(inbound),
+        peer,
+        router,
+        sessions,
+        identity,
+        decoy,
+    )
+    .await
+}
+
+/// websocket upgrades and polling requests share the http/1.1 listener
+async fn serve_http1<T>(
+    mut inbound: BufReader<T>,
+    peer: Peer,
+    router: Arc<Router>,
+    sessions: PollingSessions,
+    identity: Option<String>,
+    decoy: Option<Arc<Decoy>>,
+) -> ProxyResult<()>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let head = match http1::read_request_head(&mut inbound).await? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    if !head.is_websocket_upgrade() {
+        return serve_polling(inbound, head, peer, router, sessions, identity, decoy).await;
+    }
+    let client_addr = peer.client_addr(&head);
+    let route = match router.check(
+        &head.path,
+        head.header("Sec-WebSocket-Protocol"),
+        head.header("Authorization"),
+        identity.as_deref(),
+    ) {
+        Ok(route) => route,
+        Err(status) => {
+            info!(
+                "reject websocket upgrade on {} from {} with {}",
+                head.path, client_addr, status
+            );
+            if let Some(decoy) = decoy {
+                return decoy.serve_http1(inbound, head, None).await;
+            }
+            http1::write_response(inbound.get_mut(), status, &[]).await?;
+            return Ok(());
+        }
+    };
+    info!(
+        "correct auth, path {} of group {} from {}",
+        route.path, route.group, client_addr
+    );
+    let protocol = route.protocol.clone();
     // convert to websocket stream
     // let ws_stream = tokio_tungstenite::accept_async(inbound).await?;
     let ws_stream = tokio_tungstenite::accept_hdr_async(
-        inbound,
-        |req: &http::Request<()>,
-         res: http::Response<()>|
+        PrefixedStream::new(head.raw, inbound),
+        |_: &http::Request<()>,
+         mut res: http::Response<()>|
          -> Result<http::Response<()>, http::Response<Option<String>>> {
-            if req.headers().get("Authorization").map(|x| x.as_bytes())
-                != Some(authorization.as_bytes())
-            {
-                info!("incorrect auth");
-                return Err(http::Response::new(Some(
-                    "invalid authorization".to_string(),
-                )));
-            }
-            info!("correct auth");
+            // confirm the required subprotocol
+            if let Some(protocol) = protocol {
+                if let Ok(value) = protocol.parse() {
+                    res.headers_mut().insert("Sec-WebSocket-Protocol", value);
+                }
+            }
             Ok(res)
         },
     )
     .await?;
     info!("build websocket stream successfully");
+    proxy_websocket(ws_stream, peer, client_addr).await
+}
+
+/// serve polling requests of a keep-alive connection, a session carries one websocket
+async fn serve_polling<T>(
+    mut inbound: BufReader<T>,
+    mut head: RequestHead,
+    peer: Peer,
+    router: Arc<Router>,
+    sessions: PollingSessions,
+    identity: Option<String>,
+    decoy: Option<Arc<Decoy>>,
+) -> ProxyResult<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let body = http1::read_request_body(&mut inbound, &head).await?;
+        let (status, body) = match polling::parse_path(&head.path) {
+            Some((path, None)) if head.method == "POST" => match router.check(
+                path,
+                head.header("Sec-WebSocket-Protocol"),
+                head.header("Authorization"),
+                identity.as_deref(),
+            ) {
+                Err(status) => {
+                    info!(
+                        "reject polling session on {} from {} with {}",
+                        path,
+                        peer.client_addr(&head),
+                        status
+                    );
+                    if let Some(decoy) = decoy {
+                        return decoy.serve_http1(inbound, head, Some(body)).await;
+                    }
+                    (status, Vec::new())
+                }
+                Ok(route) => {
+                    let (id, stream) = sessions.open();
+                    let client_addr = peer.client_addr(&head);
+                    info!(
+                        "polling session {} opened for group {} from {}",
+                        id, route.group, client_addr
+                    );
+                    let peer = peer.clone();
+                    let serve = async move {
+                        let ws_stream =
+                            WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+                        proxy_websocket(ws_stream, peer, client_addr).await
+                    }
+                    .map(|r| {
+                        if let Err(e) = r {
+                            error!("Failed to transfer; error={:?}", e);
+                        }
+                    });
+                    tokio::spawn(serve);
+                    (StatusCode::OK, id.into_bytes())
+                }
+            },
+            Some((_, Some(id))) => sessions.handle(&head.method, id, body).await,
+            _ => {
+                info!("unknown http request {} {}", head.method, head.path);
+                if let Some(decoy) = decoy {
+                    return decoy.serve_http1(inbound, head, Some(body)).await;
+                }
+                (StatusCode::NOT_FOUND, Vec::new())
+            }
+        };
+        http1::write_response(inbound.get_mut(), status, &body).await?;
+        head = match http1::read_request_head(&mut inbound).await? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+    }
+}
+
+/// accept websockets sent as extended CONNECT streams (RFC 8441)
+async fn serve_h2<T>(
+    inbound: T,
+    peer: Peer,
+    router: Arc<Router>,
+    identity: Option<String>,
+    decoy: Option<Arc<Decoy>>,
+) -> ProxyResult<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::Builder::new()
+        .enable_connect_protocol()
+        .handshake(inbound)
+        .await?;
+    info!("build h2 connection successfully");
+    while let Some(request) = connection.accept().await {
+        let (request, mut respond) = request?;
+        let is_websocket = request.method() == Method::CONNECT
+            && request.extensions().get::<Protocol>().map(|p| p.as_str())
+                == Some(PROTOCOL_WEBSOCKET);
+        if !is_websocket {
+            info!("h2 request is not an extended CONNECT websocket");
+            if let Some(decoy) = &decoy {
+                spawn_decoy_h2(decoy.clone(), request, respond);
+                continue;
+            }
+            let res = http::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(())?;
+            respond.send_response(res, true)?;
+            continue;
+        }
+        let headers = request.headers();
+        let route = match router.check(
+            request.uri().path(),
+            headers.get("Sec-WebSocket-Protocol").map(|x| x.as_bytes()),
+            headers.get("Authorization").map(|x| x.as_bytes()),
+            identity.as_deref(),
+        ) {
+            Ok(route) => route,
+            Err(status) => {
+                info!("reject h2 websocket on {} with {}", request.uri(), status);
+                if let Some(decoy) = &decoy {
+                    spawn_decoy_h2(decoy.clone(), request, respond);
+                    continue;
+                }
+                let res = http::Response::builder().status(status).body(())?;
+                respond.send_response(res, true)?;
+                continue;
+            }
+        };
+        info!("correct auth, path {} of group {}", route.path, route.group);
+        let mut res = http::Response::builder();
+        if let Some(protocol) = &route.protocol {
+            res = res.header("Sec-WebSocket-Protocol", protocol.as_str());
+        }
+        let send = respond.send_response(res.body(())?, false)?;
+        let stream = H2Stream::new(send, request.into_body());
+        let peer = peer.clone();
+        let serve = async move {
+            let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+            let client_addr = peer.addr.ip().to_canonical();
+            proxy_websocket(ws_stream, peer, client_addr).await
+        }
+        .map(|r| {
+            if let Err(e) = r {
+                error!("Failed to transfer; error={:?}", e);
+            }
+        });
+        tokio::spawn(serve);
+    }
+    Ok(())
+}
+
+fn spawn_decoy_h2(
+    decoy: Arc<Decoy>,
+    request: http::Request<h2::RecvStream>,
+    respond: h2::server::SendResponse<bytes::Bytes>,
+) {
+    tokio::spawn(async move {
+        if let Err(e) = decoy.respond_h2(request, respond).await {
+            error!("decoy h2 request failed, detail is {:?}", e);
+        }
+    });
+}
+
+/// serve connect packets on an established websocket until the client goes away
+async fn proxy_websocket<T>(
+    ws_stream: WebSocketStream<T>,
+    peer: Peer,
+    client_addr: IpAddr,
+) -> ProxyResult<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     // get connect addrs from connect packet
     // let (mut input_write, mut input_read) = ws_stream.split();
     let mut ws_stream = WebSocketConnection(ws_stream);
@@ -104,6 +597,10 @@ async fn serve(
             Some(msg) => {
                 info!("get msg : {:?}", msg);
                 match msg {
+                    Ok(msg) if msg.is_close() => {
+                        info!("client closed the websocket");
+                        return Ok(());
+                    }
                     Ok(msg) => match Packet::to_packet(msg) {
                         Ok(Packet::Connect(addr)) => addr.try_into()?,
                         Ok(msg) => {
@@ -123,9 +620,860 @@ async fn serve(
                 return Ok(());
             }
         };
-        let mut outbound = TcpStream::connect(&addrs[..]).await?;
+        let mut outbound = peer.connect(client_addr, &addrs).await?;
         info!("connect to proxy addrs successfully");
         let _ = copy_bidirectional(&mut ws_stream, &mut outbound).await;
         info!("server: finish copy.....");
     }
 }
+
+async fn serve_quic(
+    endpoint: Endpoint,
+    router: Arc<Router>,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let serve = serve_quic_connection(incoming, router.clone(), send_proxy_protocol).map(|r| {
+            if let Err(e) = r {
+                error!("Failed to serve quic connection; error={:?}", e);
+            }
+        });
+        tokio::spawn(serve);
+    }
+}
+
+async fn serve_quic_connection(
+    incoming: Incoming,
+    router: Arc<Router>,
+    send_proxy_protocol: Option<ProxyProtocolVersion>,
+) -> ProxyResult<()> {
+    let connection = incoming.await?;
+    let mut peer = Peer::new(connection.remote_address(), Arc::new(Vec::new()));
+    peer.send_proxy_protocol = send_proxy_protocol;
+    info!(
+        "get new quic connection from {}",
+        connection.remote_address()
+    );
+    let identity = quic::peer_identity(&connection);
+    if let Some(identity) = &identity {
+        info!("client certificate identity is {}", identity);
+    }
+
+    // the first uni stream carries the route and the authorization
+    let hello = connection
+        .accept_uni()
+        .await?
+        .read_to_end(MAX_HELLO_LEN)
+        .await?;
+    let hello = QuicHello::decode(&hello)?;
+    let route = match router.check(
+        &hello.path,
+        hello.protocol.as_deref().map(str::as_bytes),
+        Some(&hello.authorization),
+        identity.as_deref(),
+    ) {
+        Ok(route) => route,
+        Err(status) => {
+            info!(
+                "reject quic connection on {} from {} with {}",
+                hello.path, peer.addr, status
+            );
+            connection.close(0u32.into(), status.as_str().as_bytes());
+            return Ok(());
+        }
+    };
+    info!(
+        "correct auth, path {} of group {} from {}",
+        route.path, route.group, peer.addr
+    );
+
+    tokio::spawn(relay_quic_datagrams(connection.clone()));
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                info!("quic connection closed, detail is {:?}", e);
+                return Ok(());
+            }
+        };
+        let serve = serve_quic_stream(QuicStream::new(send, recv), peer.clone()).map(|r| {
+            if let Err(e) = r {
+                error!("Failed to transfer; error={:?}", e);
+            }
+        });
+        tokio::spawn(serve);
+    }
+}
+
+async fn serve_quic_stream(mut stream: QuicStream, peer: Peer) -> ProxyResult<()> {
+    let addrs: Vec<SocketAddr> = match stream.read_packet().await? {
+        Packet::Connect(addr) => addr.try_into()?,
+        _ => return Err(ProxyError::InvalidPacketType),
+    };
+    let mut outbound = peer.connect(peer.addr.ip().to_canonical(), &addrs).await?;
+    info!("connect to proxy addrs successfully");
+    let (a_to_b, b_to_a) = copy_bidirectional(&mut stream, &mut outbound).await?;
+    info!(
+        "server: finish quic copy a_to_b {} b_to_a {}",
+        a_to_b, b_to_a
+    );
+    Ok(())
+}
+
+async fn relay_quic_datagrams(connection: Connection) {
+    let sockets: Arc<Mutex<HashMap<u32, Arc<UdpSocket>>>> = Arc::new(Mutex::new(HashMap::new()));
+    while let Ok(datagram) = connection.read_datagram().await {
+        let (id, addr, payload) = match decode_datagram(datagram) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                error!("invalid datagram from client, detail is {:?}", e);
+                continue;
+            }
+        };
+        let addrs: Vec<SocketAddr> = match addr.try_into() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("resolve udp target failed, detail is {:?}", e);
+                continue;
+            }
+        };
+        let target = match addrs.first() {
+            Some(target) => *target,
+            None => continue,
+        };
+
+        let socket = sockets.lock().unwrap().get(&id).cloned();
+        let socket = match socket {
+            Some(socket) => socket,
+            None => {
+                let bind_addr = match target {
+                    SocketAddr::V4(_) => "0.0.0.0:0",
+                    SocketAddr::V6(_) => "[::]:0",
+                };
+                let socket = match UdpSocket::bind(bind_addr).await {
+                    Ok(socket) => Arc::new(socket),
+                    Err(e) => {
+                        error!("bind udp socket failed, detail is {:?}", e);
+                        continue;
+                    }
+                };
+                sockets.lock().unwrap().insert(id, socket.clone());
+                tokio::spawn(relay_udp_replies(
+                    id,
+                    socket.clone(),
+                    connection.clone(),
+                    sockets.clone(),
+                ));
+                socket
+            }
+        };
+        if let Err(e) = socket.send_to(&payload, target).await {
+            error!("send udp packet to {} failed, detail is {:?}", target, e);
+        }
+    }
+    info!("quic datagrams closed");
+}
+
+async fn relay_udp_replies(
+    id: u32,
+    socket: Arc<UdpSocket>,
+    connection: Connection,
+    sockets: Arc<Mutex<HashMap<u32, Arc<UdpSocket>>>>,
+) {
+    let mut buf = vec![0u8; 65535];
+    while let Ok(Ok((n, from))) =
+        tokio::time::timeout(UDP_IDLE_TIMEOUT, socket.recv_from(&mut buf)).await
+    {
+        let datagram = match encode_datagram(id, &from.into(), &buf[..n]) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                info!("drop udp reply, detail is {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = connection.send_datagram(datagram) {
+            info!("send datagram back to client failed, detail is {:?}", e);
+            break;
+        }
+    }
+    sockets.lock().unwrap().remove(&id);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        codec::Addr,
+        pool::{
+            make_connection::{
+                MakeWebsocketStreamConnection, PollingFallback, POLLING_RETRY_UPGRADE,
+            },
+            retry::{CircuitBreaker, RetryPolicy},
+        },
+        transport::{
+            h2::H2Connector,
+            polling::PollingConnector,
+            quic::QuicConnector,
+            tls::{self, ClientTlsOptions, TlsPreset, ALPN_HTTP1},
+            ServerEndpoint,
+        },
+    };
+    use futures::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use tower::Service;
+
+    async fn spawn_echo() -> SocketAddr {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        echo_addr
+    }
+
+    /// tls websocket server on loopback accepting `route`, returns its port and certificate
+    async fn spawn_server(route: &str) -> (u16, rustls::Certificate) {
+        spawn_server_with(route, &ServerTlsOptions::default(), None).await
+    }
+
+    async fn spawn_server_with(
+        route: &str,
+        tls_options: &ServerTlsOptions,
+        decoy: Option<Decoy>,
+    ) -> (u16, rustls::Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key_der = rustls::PrivateKey(cert.serialize_private_key_der());
+        let cert_store = CertStore::new(vec![cert_der.clone()], key_der).unwrap();
+        let acceptor = make_acceptor(Arc::new(cert_store), tls_options, false).unwrap();
+        let port = spawn_listener(route, Some(acceptor), decoy, Vec::new(), None).await;
+        (port, cert_der)
+    }
+
+    /// tls when `acceptor` is set, plaintext otherwise, returns the port
+    /// with `proxy_protocol` set connections start with a PROXY header
+    /// and destinations get one of that version
+    async fn spawn_listener(
+        route: &str,
+        acceptor: Option<TlsAcceptor>,
+        decoy: Option<Decoy>,
+        trusted_proxies: Vec<IpRange>,
+        proxy_protocol: Option<ProxyProtocolVersion>,
+    ) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let router = Arc::new(Router::new(vec![route.parse().unwrap()], "abc".to_string()));
+        let sessions = PollingSessions::new();
+        let decoy = decoy.map(Arc::new);
+        let trusted_proxies = Arc::new(trusted_proxies);
+        tokio::spawn(async move {
+            while let Ok((inbound, peer)) = listener.accept().await {
+                let mut peer = Peer::new(peer, trusted_proxies.clone());
+                peer.send_proxy_protocol = proxy_protocol;
+                tokio::spawn(serve(
+                    inbound,
+                    peer,
+                    proxy_protocol.is_some(),
+                    router.clone(),
+                    acceptor.clone(),
+                    sessions.clone(),
+                    decoy.clone(),
+                ));
+            }
+        });
+        port
+    }
+
+    async fn assert_websocket_echo(mut mt: MakeWebsocketStreamConnection, echo_addr: SocketAddr) {
+        let outbound = mt.call(()).await.unwrap();
+        let mut outbound = WebSocketConnection(outbound.0);
+        let addr_msg: Message = Packet::Connect(echo_addr.into()).try_into().unwrap();
+        outbound.0.send(addr_msg).await.unwrap();
+        outbound.write_all(b"hello websocket").await.unwrap();
+        outbound.flush().await.unwrap();
+        let mut buf = [0u8; 15];
+        outbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello websocket");
+    }
+
+    fn make_connection(
+        port: u16,
+        cert: rustls::Certificate,
+        alpn: &[u8],
+    ) -> MakeWebsocketStreamConnection {
+        let tls_options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            ..Default::default()
+        };
+        make_connection_with(port, &tls_options, alpn)
+    }
+
+    fn make_connection_with(
+        port: u16,
+        tls_options: &ClientTlsOptions,
+        alpn: &[u8],
+    ) -> MakeWebsocketStreamConnection {
+        let tls_connector = tls::connector(tls::client_config(&[alpn], tls_options).unwrap());
+        let h2 = if alpn == ALPN_H2 {
+            Some(H2Connector::new(tls_connector.clone()))
+        } else {
+            None
+        };
+        // host header, sni and tcp address all differ, as behind a cdn
+        let mut endpoint = ServerEndpoint::new("front.example", "/ws/tunnel").unwrap();
+        endpoint.sni = "localhost".to_string();
+        endpoint.connect_addr = format!("127.0.0.1:{}", port);
+        MakeWebsocketStreamConnection {
+            endpoint: Arc::new(endpoint),
+            authorization: Arc::new("abc".to_string()),
+            polling: PollingConnector::new(tls_connector.clone()),
+            prefer_polling: Arc::new(PollingFallback::new(POLLING_RETRY_UPGRADE)),
+            retry: RetryPolicy::default(),
+            breaker: Arc::new(CircuitBreaker::new(5, Duration::from_secs(10))),
+            tls_connector,
+            h2,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_websocket_http1_and_h2() {
+        let echo_addr = spawn_echo().await;
+        let (port, cert) = spawn_server("/ws/tunnel").await;
+
+        assert_websocket_echo(make_connection(port, cert.clone(), ALPN_HTTP1), echo_addr).await;
+
+        // two websockets multiplexed on the same http/2 connection
+        let mt = make_connection(port, cert, ALPN_H2);
+        assert_websocket_echo(mt.clone(), echo_addr).await;
+        assert_websocket_echo(mt, echo_addr).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_websocket_over_polling() {
+        let echo_addr = spawn_echo().await;
+        let (port, cert) = spawn_server("/ws/tunnel").await;
+
+        // as if the upgrade had failed before
+        let mt = make_connection(port, cert, ALPN_HTTP1);
+        mt.prefer_polling.prefer();
+        assert_websocket_echo(mt.clone(), echo_addr).await;
+        assert_websocket_echo(mt, echo_addr).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_websocket_routes() {
+        let echo_addr = spawn_echo().await;
+        let (port, cert) = spawn_server("/ws/tunnel,group=a,protocol=ss").await;
+
+        for alpn in [ALPN_HTTP1, ALPN_H2] {
+            // missing subprotocol
+            let mut mt = make_connection(port, cert.clone(), alpn);
+            assert!(mt.call(()).await.is_err());
+
+            // unknown path
+            let mut mt = make_connection(port, cert.clone(), alpn);
+            let endpoint = Arc::make_mut(&mut mt.endpoint);
+            endpoint.protocol = Some("ss".to_string());
+            endpoint.url.set_path("/ws/other");
+            assert!(mt.call(()).await.is_err());
+
+            let mut mt = make_connection(port, cert.clone(), alpn);
+            Arc::make_mut(&mut mt.endpoint).protocol = Some("ss".to_string());
+            assert_websocket_echo(mt, echo_addr).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_tls_trust() {
+        let echo_addr = spawn_echo().await;
+        let (port, cert) = spawn_server("/ws/tunnel").await;
+        let pin = tls::spki_sha256(&cert.0).unwrap();
+
+        // a certificate authority which does not parse is an error up front
+        let options = ClientTlsOptions {
+            ca_certs: vec![rustls::Certificate(b"not a certificate".to_vec())],
+            ..Default::default()
+        };
+        assert!(matches!(
+            tls::client_config(&[ALPN_HTTP1], &options),
+            Err(ProxyError::InvalidCert)
+        ));
+
+        // self signed certificate is unknown to the native roots
+        let options = ClientTlsOptions::default();
+        assert!(make_connection_with(port, &options, ALPN_HTTP1)
+            .call(())
+            .await
+            .is_err());
+
+        let options = ClientTlsOptions {
+            danger_accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert_websocket_echo(make_connection_with(port, &options, ALPN_HTTP1), echo_addr).await;
+
+        let options = ClientTlsOptions {
+            ca_certs: vec![cert.clone()],
+            spki_pins: vec![pin],
+            ..Default::default()
+        };
+        assert_websocket_echo(make_connection_with(port, &options, ALPN_H2), echo_addr).await;
+
+        // pins apply even when verification is off
+        let options = ClientTlsOptions {
+            spki_pins: vec![[0u8; 32]],
+            danger_accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert!(make_connection_with(port, &options, ALPN_HTTP1)
+            .call(())
+            .await
+            .is_err());
+    }
+
+    /// client certificate for `name` signed by `ca`
+    fn issue_client_cert(
+        ca: &rcgen::Certificate,
+        name: &str,
+    ) -> (Vec<rustls::Certificate>, rustls::PrivateKey) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (
+            vec![rustls::Certificate(
+                cert.serialize_der_with_signer(ca).unwrap(),
+            )],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mutual_tls() {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let ca_der = rustls::Certificate(ca.serialize_der().unwrap());
+
+        let echo_addr = spawn_echo().await;
+        let tls_options = ServerTlsOptions {
+            client_cas: vec![ca_der],
+            ..Default::default()
+        };
+        let (port, cert) = spawn_server_with(
+            "/ws/tunnel,identity=alice.clients.example",
+            &tls_options,
+            None,
+        )
+        .await;
+
+        // no client certificate
+        let mut mt = make_connection(port, cert.clone(), ALPN_HTTP1);
+        assert!(mt.call(()).await.is_err());
+
+        // signed by the ca but not allowed on the route
+        let options = ClientTlsOptions {
+            ca_certs: vec![cert.clone()],
+            client_cert: Some(issue_client_cert(&ca, "eve.clients.example")),
+            ..Default::default()
+        };
+        assert!(make_connection_with(port, &options, ALPN_HTTP1)
+            .call(())
+            .await
+            .is_err());
+
+        let options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            client_cert: Some(issue_client_cert(&ca, "alice.clients.example")),
+            ..Default::default()
+        };
+        assert_websocket_echo(make_connection_with(port, &options, ALPN_HTTP1), echo_addr).await;
+        assert_websocket_echo(make_connection_with(port, &options, ALPN_H2), echo_addr).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_tls_policy() {
+        let tls_options = ServerTlsOptions {
+            versions: vec![rustls::ProtocolVersion::TLSv1_3],
+            cipher_suites: vec!["TLS13_CHACHA20_POLY1305_SHA256".to_string()],
+            alpn: vec![ALPN_HTTP1.to_vec()],
+            session_tickets: true,
+            ..Default::default()
+        };
+        let (port, cert) = spawn_server_with("/ws/tunnel", &tls_options, None).await;
+        let mut endpoint = ServerEndpoint::new("localhost", "/").unwrap();
+        endpoint.connect_addr = format!("127.0.0.1:{}", port);
+        let client_options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            ..Default::default()
+        };
+
+        let config = tls::client_config(&[ALPN_H2, ALPN_HTTP1], &client_options).unwrap();
+        let stream = tls::connect(&tls::connector(config), &endpoint)
+            .await
+            .unwrap();
+        let session = stream.get_ref().1;
+        assert_eq!(session.get_alpn_protocol(), Some(ALPN_HTTP1));
+        assert_eq!(
+            session.get_protocol_version(),
+            Some(rustls::ProtocolVersion::TLSv1_3)
+        );
+        assert_eq!(
+            session.get_negotiated_ciphersuite().map(|s| s.suite),
+            Some(rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+        );
+
+        let mut config = tls::client_config(&[ALPN_HTTP1], &client_options).unwrap();
+        config.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+        assert!(tls::connect(&tls::connector(config), &endpoint)
+            .await
+            .is_err());
+
+        // unknown names and versions left without a cipher suite are refused up front
+        for (versions, cipher_suites) in [
+            (vec![], vec!["TLS_NOT_A_SUITE".to_string()]),
+            (
+                vec![rustls::ProtocolVersion::TLSv1_3],
+                vec!["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string()],
+            ),
+        ] {
+            let tls_options = ServerTlsOptions {
+                versions,
+                cipher_suites,
+                ..Default::default()
+            };
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let cert_store = CertStore::new(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+            assert!(make_acceptor(Arc::new(cert_store), &tls_options, false).is_err());
+        }
+    }
+
+    /// plain websocket to `target` as nginx passes it on after terminating tls
+    async fn assert_plaintext_echo(stream: TcpStream, port: u16, target: SocketAddr) {
+        let request = http::Request::get(format!("ws://127.0.0.1:{}/ws/tunnel", port))
+            .header("Host", "front.example")
+            .header("Authorization", "abc")
+            .header("X-Forwarded-For", "203.0.113.9")
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        let (ws_stream, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .unwrap();
+        let mut outbound = WebSocketConnection(ws_stream);
+        let addr_msg: Message = Packet::Connect(target.into()).try_into().unwrap();
+        outbound.0.send(addr_msg).await.unwrap();
+        outbound.write_all(b"hello plaintext").await.unwrap();
+        outbound.flush().await.unwrap();
+        let mut buf = [0u8; 15];
+        outbound.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello plaintext");
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_behind_proxy() {
+        let echo_addr = spawn_echo().await;
+        let trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+        let port = spawn_listener("/ws/tunnel", None, None, trusted_proxies, None).await;
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert_plaintext_echo(stream, port, echo_addr).await;
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        // destination expecting a PROXY header, echoes what follows
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let (header_tx, header_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = destination.accept().await.unwrap();
+            let header = proxy_protocol::read_header(&mut stream).await.unwrap();
+            header_tx.send(header).unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        // the load balancer in front is the proxy setting X-Forwarded-For
+        let trusted_proxies = vec!["198.51.100.7".parse().unwrap()];
+        let port = spawn_listener(
+            "/ws/tunnel",
+            None,
+            None,
+            trusted_proxies,
+            Some(ProxyProtocolVersion::V2),
+        )
+        .await;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let header = proxy_protocol::ProxyHeader::new(
+            "198.51.100.7:40000".parse().unwrap(),
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+        );
+        stream
+            .write_all(&header.encode(ProxyProtocolVersion::V1))
+            .await
+            .unwrap();
+        assert_plaintext_echo(stream, port, destination_addr).await;
+        let header = header_rx.await.unwrap().unwrap();
+        assert_eq!(header.source, "203.0.113.9:0".parse().unwrap());
+        assert_eq!(header.destination, destination_addr);
+
+        // connections without the header are dropped
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("ws://127.0.0.1:{}/ws/tunnel", port);
+        assert!(tokio_tungstenite::client_async(request, stream)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decoy() {
+        // plain http server standing in for the real website
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = upstream.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    while let Ok(Some(_)) = http1::read_request_head(&mut stream).await {
+                        let res = "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nwebsite";
+                        stream.get_mut().write_all(res.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        let decoy = Decoy::Upstream(upstream_addr.to_string());
+        let (port, cert) =
+            spawn_server_with("/ws/tunnel", &ServerTlsOptions::default(), Some(decoy)).await;
+        let mut endpoint = ServerEndpoint::new("localhost", "/").unwrap();
+        endpoint.connect_addr = format!("127.0.0.1:{}", port);
+        let tls_options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            ..Default::default()
+        };
+
+        // ordinary requests and upgrades with a wrong authorization look the same
+        let config = tls::client_config(&[ALPN_HTTP1], &tls_options).unwrap();
+        let connector = tls::connector(config);
+        let upgrade = [
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Authorization", "wrong"),
+        ];
+        for (path, headers) in [("/", &[][..]), ("/ws/tunnel", &upgrade[..])] {
+            let stream = tls::connect(&connector, &endpoint).await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let res = http1::request(&mut stream, "GET", path, "localhost", headers, b"")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(res.body, b"website");
+        }
+
+        // requests on h2 are answered by the decoy as well
+        let config = tls::client_config(&[ALPN_H2], &tls_options).unwrap();
+        let stream = tls::connect(&tls::connector(config), &endpoint)
+            .await
+            .unwrap();
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        for (path, status) in [("/", StatusCode::OK), ("/favicon.ico", StatusCode::OK)] {
+            let req = http::Request::get(format!("https://localhost{}", path))
+                .body(())
+                .unwrap();
+            let (res, _) = client.send_request(req, true).unwrap();
+            let res = res.await.unwrap();
+            assert_eq!(res.status(), status);
+            let mut body = res.into_body();
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            assert_eq!(data, b"website");
+        }
+
+        let (port, cert) = spawn_server_with(
+            "/ws/tunnel",
+            &ServerTlsOptions::default(),
+            Some(Decoy::Builtin),
+        )
+        .await;
+        endpoint.connect_addr = format!("127.0.0.1:{}", port);
+        let tls_options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            ..Default::default()
+        };
+        let config = tls::client_config(&[ALPN_HTTP1], &tls_options).unwrap();
+        let stream = tls::connect(&tls::connector(config), &endpoint)
+            .await
+            .unwrap();
+        let mut stream = BufReader::new(stream);
+        for (path, status) in [
+            ("/", StatusCode::OK),
+            ("/.git/config", StatusCode::NOT_FOUND),
+        ] {
+            let res = http1::request(&mut stream, "GET", path, "localhost", &[], b"")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(res.status, status);
+            assert_eq!(res.header("Content-Type"), Some(&b"text/html"[..]));
+        }
+
+        // a chunked body is read, not taken for the next request
+        let requests = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nGET \r\n0\r\n\r\n\
+            GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        stream.get_mut().write_all(requests.as_bytes()).await.unwrap();
+        for status in [StatusCode::METHOD_NOT_ALLOWED, StatusCode::OK] {
+            let res = http1::read_response(&mut stream).await.unwrap().unwrap();
+            assert_eq!(res.status, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_tls_preset() {
+        let (port, cert) =
+            spawn_server_with("/ws/tunnel", &ServerTlsOptions::default(), None).await;
+        let mut endpoint = ServerEndpoint::new("localhost", "/").unwrap();
+        endpoint.connect_addr = format!("127.0.0.1:{}", port);
+        let mut client_options = ClientTlsOptions {
+            ca_certs: vec![cert],
+            ..Default::default()
+        };
+        "Firefox"
+            .parse::<TlsPreset>()
+            .unwrap()
+            .apply(&mut client_options);
+
+        // h2 connections offer the http/1.1 fallback, http/1.1 ones never offer h2
+        for (alpn, offered) in [
+            (ALPN_H2, vec![ALPN_H2, ALPN_HTTP1]),
+            (ALPN_HTTP1, vec![ALPN_HTTP1]),
+        ] {
+            let config = tls::client_config(&[alpn], &client_options).unwrap();
+            assert_eq!(config.alpn_protocols, offered);
+            let stream = tls::connect(&tls::connector(config), &endpoint)
+                .await
+                .unwrap();
+            let session = stream.get_ref().1;
+            assert_eq!(session.get_alpn_protocol(), Some(alpn));
+            assert_eq!(
+                session.get_negotiated_ciphersuite().map(|s| s.suite),
+                Some(rustls::CipherSuite::TLS13_AES_128_GCM_SHA256)
+            );
+        }
+
+        // without sni the server falls back to its default certificate
+        client_options.enable_sni = false;
+        client_options.session_cache_size = 0;
+        let config = tls::client_config(&[ALPN_HTTP1], &client_options).unwrap();
+        tls::connect(&tls::connector(config), &endpoint)
+            .await
+            .unwrap();
+
+        client_options.alpn = vec![ALPN_HTTP1.to_vec()];
+        assert!(tls::client_config(&[ALPN_H2], &client_options).is_err());
+        assert!("curl".parse::<TlsPreset>().is_err());
+    }
+
+    /// quic server on loopback accepting `routes`, returns a connector trusting it
+    async fn spawn_quic_server(routes: &[&str]) -> QuicConnector {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key_der = rustls::PrivateKey(cert.serialize_private_key_der());
+
+        let listen_addr = tokio::net::lookup_host("localhost:0")
+            .await
+            .unwrap()
+            .next()
+            .unwrap();
+        let endpoint = make_server_endpoint(
+            listen_addr,
+            Arc::new(CertStore::new(vec![cert_der.clone()], key_der).unwrap()),
+            &ServerTlsOptions::default(),
+        )
+        .unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let routes = routes.iter().map(|r| r.parse().unwrap()).collect();
+        let router = Router::new(routes, "abc".to_string());
+        tokio::spawn(serve_quic(endpoint, Arc::new(router), None));
+
+        let mut connector = QuicConnector::new(format!("localhost:{}", port), "abc".to_string());
+        connector
+            .set_tls_options(&ClientTlsOptions {
+                spki_pins: vec![tls::spki_sha256(&cert_der.0).unwrap()],
+                ca_certs: vec![cert_der],
+                ..Default::default()
+            })
+            .unwrap();
+        connector
+    }
+
+    async fn quic_echoes(connector: &QuicConnector, echo_addr: SocketAddr) -> bool {
+        let mut stream = match connector.open(echo_addr.into()).await {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        let mut buf = [0u8; 10];
+        stream.write_all(b"hello quic").await.is_ok()
+            && stream.read_exact(&mut buf).await.is_ok()
+            && &buf == b"hello quic"
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quic_loopback() {
+        let connector = spawn_quic_server(&[]).await;
+        let echo_addr = spawn_echo().await;
+        // udp echo target
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (n, from) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..n], from).await.unwrap();
+        });
+
+        assert!(quic_echoes(&connector, echo_addr).await);
+
+        let mut association = connector.associate().await.unwrap();
+        association
+            .send(&udp_echo_addr.into(), b"hello datagram")
+            .unwrap();
+        let (from, payload) = association.recv().await.unwrap();
+        assert_eq!(from, Addr::from(udp_echo_addr));
+        assert_eq!(&payload[..], b"hello datagram");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quic_routes() {
+        let echo_addr = spawn_echo().await;
+        let connector = spawn_quic_server(&[
+            "/ws/tunnel,protocol=ss",
+            "/ws/private,identity=alice.clients.example",
+        ])
+        .await;
+        // `/` is not routed
+        assert!(!quic_echoes(&connector, echo_addr).await);
+
+        let mut restricted = connector.clone();
+        restricted.set_route("/ws/private".to_string(), None);
+        assert!(!quic_echoes(&restricted, echo_addr).await);
+
+        let mut routed = connector.clone();
+        routed.set_route("/ws/tunnel".to_string(), Some("ss".to_string()));
+        assert!(quic_echoes(&routed, echo_addr).await);
+    }
+}
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+
+use http::StatusCode;
+use log::info;
+use ring::constant_time;
+
+/// group of the route used when none is configured
+pub const DEFAULT_GROUP: &str = "default";
+
+/// A path the server accepts websocket upgrades on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub path: String,
+    /// `Sec-WebSocket-Protocol` the client has to offer, any when unset
+    pub protocol: Option<String>,
+    /// user group of the clients coming in on this path
+    pub group: String,
+    /// authorization accepted on this path, the server wide one when unset
+    pub authorization: Option<String>,
+    /// client certificate identities allowed on this path, anyone when empty
+    pub identities: Vec<String>,
+}
+
+impl Route {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: normalize(path).to_string(),
+            protocol: None,
+            group: DEFAULT_GROUP.to_string(),
+            authorization: None,
+            identities: Vec::new(),
+        }
+    }
+}
+
+/// `/ws/tunnel,group=team,protocol=ss,authorization=token,identity=alice`,
+/// everything but the path is optional, `identity` can be repeated
+impl FromStr for Route {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let path = parts.next().unwrap_or_default();
+        if !path.starts_with('/') {
+            return Err(format!("route path `{}` should start with /", path));
+        }
+        let mut route = Route::new(path);
+        for part in parts {
+            match part.split_once('=') {
+                Some(("group", v)) => route.group = v.to_string(),
+                Some(("protocol", v)) => route.protocol = Some(v.to_string()),
+                Some(("authorization", v)) => route.authorization = Some(v.to_string()),
+                Some(("identity", v)) => route.identities.push(v.to_string()),
+                _ => return Err(format!("unknown route option `{}`", part)),
+            }
+        }
+        Ok(route)
+    }
+}
+
+/// drop the query and trailing slashes, so `/ws/` and `/ws?a=b` both route as `/ws`
+fn normalize(path: &str) -> &str {
+    let path = path.split('?').next().unwrap_or_default();
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+/// Picks the route of an upgrade request, everything else is rejected
+pub struct Router {
+    routes: Vec<Route>,
+    authorization: String,
+}
+
+impl Router {
+    /// `authorization` applies to routes without their own, no routes means `/` only
+    pub fn new(mut routes: Vec<Route>, authorization: String) -> Self {
+        if routes.is_empty() {
+            routes.push(Route::new("/"));
+        }
+        Self {
+            routes,
+            authorization,
+        }
+    }
+
+    /// route of a request, or the status to answer it with, always 404 so probes
+    /// cannot tell a protected path from none, the reason is logged instead,
+    /// `identity` comes from the client certificate if one was required
+    pub fn check(
+        &self,
+        path: &str,
+        protocol: Option<&[u8]>,
+        authorization: Option<&[u8]>,
+        identity: Option<&str>,
+    ) -> Result<&Route, StatusCode> {
+        let path = normalize(path);
+        let route = self.routes.iter().find(|r| r.path == path).ok_or_else(|| {
+            info!("no route for path {}", path);
+            StatusCode::NOT_FOUND
+        })?;
+        if let Some(expected) = &route.protocol {
+            // the client may offer a comma separated list
+            let offered = protocol
+                .and_then(|p| std::str::from_utf8(p).ok())
+                .map(|p| p.split(',').any(|p| p.trim() == expected))
+                .unwrap_or(false);
+            if !offered {
+                info!("subprotocol of path {} not offered", path);
+                return Err(StatusCode::NOT_FOUND);
+            }
+        }
+        let expected = route.authorization.as_ref().unwrap_or(&self.authorization);
+        let authorized = authorization
+            .map(|a| constant_time::verify_slices_are_equal(a, expected.as_bytes()).is_ok())
+            .unwrap_or(false);
+        if !authorized {
+            info!("wrong authorization on path {}", path);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        if !route.identities.is_empty()
+            && !identity
+                .map(|id| route.identities.iter().any(|i| i == id))
+                .unwrap_or(false)
+        {
+            info!("identity {:?} not allowed on path {}", identity, path);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(route)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_router() {
+        let routes = vec![
+            "/ws/a,group=a,protocol=ss".parse().unwrap(),
+            "/ws/b/,group=b,authorization=b-token".parse().unwrap(),
+        ];
+        let router = Router::new(routes, "abc".to_string());
+
+        let route = router
+            .check("/ws/a?x=1", Some(b"chat, ss"), Some(b"abc"), None)
+            .unwrap();
+        assert_eq!(route.group, "a");
+        assert_eq!(
+            router.check("/ws/a", None, Some(b"abc"), None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            router
+                .check("/ws/b", None, Some(b"b-token"), None)
+                .unwrap()
+                .group,
+            "b"
+        );
+        assert_eq!(
+            router.check("/ws/b", None, Some(b"abc"), None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            router.check("/ws/b", None, None, None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            router.check("/", None, Some(b"abc"), None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert!("ws,group=a".parse::<Route>().is_err());
+
+        let router = Router::new(
+            vec!["/,identity=alice,identity=bob".parse().unwrap()],
+            "abc".to_string(),
+        );
+        assert!(router.check("/", None, Some(b"abc"), Some("bob")).is_ok());
+        assert_eq!(
+            router
+                .check("/", None, Some(b"abc"), Some("eve"))
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            router.check("/", None, Some(b"abc"), None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
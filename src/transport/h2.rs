@@ -0,0 +1,230 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures::ready;
+use h2::{client::SendRequest, ext::Protocol, Ping, RecvStream, SendStream};
+use http::{Method, Request, StatusCode};
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    transport::{tls, BoxStream, ServerEndpoint},
+};
+
+/// `:protocol` pseudo header of an extended CONNECT websocket (RFC 8441)
+pub const PROTOCOL_WEBSOCKET: &str = "websocket";
+
+/// One http/2 stream, carries the frames of one websocket
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    // data frame received but not read yet
+    pending: Bytes,
+}
+
+impl H2Stream {
+    pub fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+fn into_io_error(e: h2::Error) -> std::io::Error {
+    if e.is_io() {
+        return e.into_io().unwrap();
+    }
+    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e)
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        // empty data frames are legal, only the end of stream is eof
+        while self.pending.is_empty() {
+            match ready!(self.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.pending = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+                // end of stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = std::cmp::min(buf.remaining(), self.pending.len());
+        buf.put_slice(&self.pending[..n]);
+        self.pending.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send.reserve_capacity(buf.len());
+        match ready!(self.send.poll_capacity(cx)) {
+            Some(Ok(n)) => {
+                let n = std::cmp::min(n, buf.len());
+                self.send
+                    .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                    .map_err(into_io_error)?;
+                Poll::Ready(Ok(n))
+            }
+            Some(Err(e)) => Poll::Ready(Err(into_io_error(e))),
+            None => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        // h2 connection task flushes frames on its own
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let _ = self.send.send_data(Bytes::new(), true);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Client side of websocket over http/2, every websocket is a stream of one shared connection
+#[derive(Clone)]
+pub struct H2Connector {
+    tls_connector: TlsConnector,
+    send_request: Arc<tokio::sync::Mutex<Option<SendRequest<Bytes>>>>,
+}
+
+impl H2Connector {
+    /// `tls_connector` should offer `h2` via alpn
+    pub fn new(tls_connector: TlsConnector) -> Self {
+        Self {
+            tls_connector,
+            send_request: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    async fn send_request(&self, endpoint: &ServerEndpoint) -> ProxyResult<SendRequest<Bytes>> {
+        let mut send_request = self.send_request.lock().await;
+        if let Some(s) = send_request.as_ref() {
+            // a closed connection fails on ready
+            if let Ok(s) = s.clone().ready().await {
+                return Ok(s);
+            }
+        }
+        let s = self.connect(endpoint).await?;
+        *send_request = Some(s.clone());
+        Ok(s)
+    }
+
+    async fn connect(&self, endpoint: &ServerEndpoint) -> ProxyResult<SendRequest<Bytes>> {
+        let tls = tls::connect(&self.tls_connector, endpoint).await?;
+        let (send_request, mut connection) = h2::client::handshake(tls).await?;
+        let mut ping_pong = connection.ping_pong().expect("taken only once");
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("h2 connection error, detail is {:?}", e);
+            }
+        });
+        // server settings precede the pong, so extended CONNECT support is known afterwards
+        ping_pong.ping(Ping::opaque()).await?;
+        let send_request = send_request.ready().await?;
+        info!("h2 connection to {} established", endpoint.connect_addr);
+        Ok(send_request)
+    }
+
+    /// open an extended CONNECT stream and run the websocket protocol on it
+    pub async fn open_websocket(
+        &self,
+        endpoint: &ServerEndpoint,
+        authorization: &str,
+    ) -> ProxyResult<WebSocketStream<BoxStream>> {
+        // `:authority` comes from the url, so it carries the configured host
+        let mut url = endpoint.url.clone();
+        let _ = url.set_scheme("https");
+        let mut send_request = self.send_request(endpoint).await?;
+        if !send_request.is_extended_connect_protocol_enabled() {
+            return Err(ProxyError::Unknown(
+                "server does not support extended CONNECT".to_string(),
+            ));
+        }
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(url.as_str())
+            .extension(Protocol::from_static(PROTOCOL_WEBSOCKET))
+            .header("sec-websocket-version", "13")
+            .header("Authorization", authorization);
+        if let Some(protocol) = &endpoint.protocol {
+            req = req.header("sec-websocket-protocol", protocol.as_str());
+        }
+        let req = req.body(())?;
+        let (response, send) = send_request.send_request(req, false)?;
+        let response = response.await?;
+        if response.status() != StatusCode::OK {
+            return Err(ProxyError::InvalidServerStatus {
+                expected: StatusCode::OK.to_string(),
+                found: response.status().to_string(),
+            });
+        }
+        let stream = H2Stream::new(send, response.into_body());
+        Ok(
+            WebSocketStream::from_raw_socket(Box::new(stream) as BoxStream, Role::Client, None)
+                .await,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_empty_data_frame() {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut server = h2::server::handshake(server_io).await.unwrap();
+            let (_, mut respond) = server.accept().await.unwrap().unwrap();
+            let response = http::Response::new(());
+            let mut send = respond.send_response(response, false).unwrap();
+            send.send_data(Bytes::new(), false).unwrap();
+            send.send_data(Bytes::from_static(b"hello"), true).unwrap();
+            // drive the connection until the client hangs up
+            while server.accept().await.is_some() {}
+        });
+
+        let (client, connection) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let request = Request::builder()
+            .uri("http://localhost/")
+            .body(())
+            .unwrap();
+        let (response, send) = client.send_request(request, false).unwrap();
+        let recv = response.await.unwrap().into_body();
+        let mut stream = H2Stream::new(send, recv);
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use http::StatusCode;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    ReadBuf,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    transport::{tls, BoxStream, ServerEndpoint},
+};
+
+/// request or response heads longer than this are rejected
+pub const MAX_HEAD_LEN: usize = 8192;
+/// bodies longer than this are rejected
+pub const MAX_BODY_LEN: usize = 1 << 20;
+const MAX_HEADERS: usize = 64;
+
+/// Parsed head of an http/1.1 request, `raw` keeps the bytes as received
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub raw: Vec<u8>,
+}
+
+fn find_header<'a>(headers: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_slice())
+}
+
+fn is_chunked(headers: &[(String, Vec<u8>)]) -> bool {
+    find_header(headers, "Transfer-Encoding")
+        .map(|v| v.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+        .unwrap_or(false)
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn content_length(&self) -> ProxyResult<usize> {
+        content_length(self.header("Content-Length"))
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        is_chunked(&self.headers)
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("Upgrade")
+            .map(|v| v.eq_ignore_ascii_case(b"websocket"))
+            .unwrap_or(false)
+    }
+}
+
+/// Whole http/1.1 response read by `request`
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        find_header(&self.headers, name)
+    }
+}
+
+fn content_length(value: Option<&[u8]>) -> ProxyResult<usize> {
+    let len = match value {
+        Some(v) => std::str::from_utf8(v)
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| ProxyError::InvalidHttpMessage("invalid content length".to_string()))?,
+        None => 0,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(ProxyError::InvalidHttpMessage(format!(
+            "body of {} bytes is too large",
+            len
+        )));
+    }
+    Ok(len)
+}
+
+/// read up to and including the empty line, `None` if the peer closed before sending anything
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> ProxyResult<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        // a line never ending must not grow the buffer either
+        let limit = (MAX_HEAD_LEN + 1 - head.len()) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if head.ends_with(b"\r\n\r\n") || head == b"\r\n" {
+            return Ok(Some(head));
+        }
+        if head.len() > MAX_HEAD_LEN {
+            return Err(ProxyError::InvalidHttpMessage(
+                "head is too large".to_string(),
+            ));
+        }
+    }
+}
+
+pub async fn read_request_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> ProxyResult<Option<RequestHead>> {
+    let raw = match read_head(reader).await? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    if req.parse(&raw)?.is_partial() {
+        return Err(ProxyError::InvalidHttpMessage(
+            "partial request head".to_string(),
+        ));
+    }
+    let method = req.method.unwrap_or_default().to_string();
+    let path = req.path.unwrap_or_default().to_string();
+    let headers = req
+        .headers
+        .iter()
+        .map(|h| (h.name.to_string(), h.value.to_vec()))
+        .collect();
+    Ok(Some(RequestHead {
+        method,
+        path,
+        headers,
+        raw,
+    }))
+}
+
+/// the body of a request, chunked or as long as `Content-Length` says
+pub async fn read_request_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    head: &RequestHead,
+) -> ProxyResult<Vec<u8>> {
+    if head.is_chunked() {
+        return read_chunked_body(reader).await;
+    }
+    read_body(reader, head.content_length()?).await
+}
+
+pub async fn read_body<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> ProxyResult<Vec<u8>> {
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+// one line of a chunked body, no longer than a head may be
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> ProxyResult<Vec<u8>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_HEAD_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.len() > MAX_HEAD_LEN {
+        return Err(ProxyError::InvalidHttpMessage(
+            "line is too long".to_string(),
+        ));
+    }
+    Ok(line)
+}
+
+/// read a `Transfer-Encoding: chunked` body, trailers are dropped
+async fn read_chunked_body<R: AsyncBufRead + Unpin>(reader: &mut R) -> ProxyResult<Vec<u8>> {
+    let invalid = || ProxyError::InvalidHttpMessage("invalid chunk size".to_string());
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        let line = std::str::from_utf8(&line).map_err(|_| invalid())?;
+        // chunk extensions follow a `;`
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        if size > MAX_BODY_LEN - body.len() {
+            return Err(ProxyError::InvalidHttpMessage(
+                "chunked body is too large".to_string(),
+            ));
+        }
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        read_line(reader).await?;
+    }
+    // trailers all together are held to the head limit
+    let mut trailers = 0;
+    loop {
+        let line = read_line(reader).await?;
+        if line.is_empty() || line.trim_ascii().is_empty() {
+            return Ok(body);
+        }
+        trailers += line.len();
+        if trailers > MAX_HEAD_LEN {
+            return Err(ProxyError::InvalidHttpMessage(
+                "trailers are too large".to_string(),
+            ));
+        }
+    }
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: StatusCode,
+    body: &[u8],
+) -> ProxyResult<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// send one request on a keep-alive connection and read the whole response,
+/// `None` if the connection was closed before any response byte arrived
+pub async fn request<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    method: &str,
+    path: &str,
+    host: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> ProxyResult<Option<Response>> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    read_response(stream).await
+}
+
+/// read a whole response, `None` if the connection was closed before any of it arrived
+pub async fn read_response<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+) -> ProxyResult<Option<Response>> {
+    let raw = match read_head(stream).await? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    if res.parse(&raw)?.is_partial() {
+        return Err(ProxyError::InvalidHttpMessage(
+            "partial response head".to_string(),
+        ));
+    }
+    let status = StatusCode::from_u16(res.code.unwrap_or_default())
+        .map_err(|e| ProxyError::InvalidHttpMessage(e.to_string()))?;
+    let headers: Vec<_> = res
+        .headers
+        .iter()
+        .map(|h| (h.name.to_string(), h.value.to_vec()))
+        .collect();
+    let body = if is_chunked(&headers) {
+        read_chunked_body(stream).await?
+    } else {
+        let len = content_length(find_header(&headers, "Content-Length"))?;
+        read_body(stream, len).await?
+    };
+    Ok(Some(Response {
+        status,
+        headers,
+        body,
+    }))
+}
+
+/// Keep-alive https connection to one endpoint, reconnects when needed
+pub struct HttpConnection {
+    tls_connector: TlsConnector,
+    pub endpoint: ServerEndpoint,
+    stream: Option<BufReader<BoxStream>>,
+}
+
+impl HttpConnection {
+    /// `tls_connector` should offer `http/1.1` via alpn
+    pub fn new(tls_connector: TlsConnector, endpoint: ServerEndpoint) -> Self {
+        Self {
+            tls_connector,
+            endpoint,
+            stream: None,
+        }
+    }
+
+    pub async fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> ProxyResult<Response> {
+        let host = self.endpoint.host();
+        // a reused connection may have been closed by the server or a proxy meanwhile, retry once
+        let reused = self.stream.is_some();
+        for _ in 0..2 {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => {
+                    let tls = tls::connect(&self.tls_connector, &self.endpoint).await?;
+                    self.stream
+                        .insert(BufReader::new(Box::new(tls) as BoxStream))
+                }
+            };
+            match request(stream, method, path, &host, headers, body).await {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) if reused => self.stream = None,
+                Ok(None) => break,
+                Err(e) => {
+                    self.stream = None;
+                    return Err(e);
+                }
+            }
+        }
+        self.stream = None;
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+/// Replays bytes already consumed from `inner` before reading from it again
+pub struct PrefixedStream<T> {
+    prefix: Bytes,
+    inner: T,
+}
+
+impl<T> PrefixedStream<T> {
+    pub fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Self {
+            prefix: Bytes::from(prefix),
+            inner,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PrefixedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = std::cmp::min(buf.remaining(), self.prefix.len());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_head_limit() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody";
+        let mut reader = &head[..];
+        let parsed = read_request_head(&mut reader).await.unwrap().unwrap();
+        assert_eq!(parsed.path, "/");
+        assert_eq!(reader, b"body");
+
+        // a single endless line
+        let mut endless = BufReader::new(tokio::io::repeat(b'a'));
+        assert!(matches!(
+            read_request_head(&mut endless).await,
+            Err(ProxyError::InvalidHttpMessage(_))
+        ));
+        let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..MAX_HEAD_LEN / 8 {
+            long.extend_from_slice(b"A: b\r\n");
+        }
+        assert!(read_request_head(&mut &long[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_chunked_body() {
+        let chunked = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: a\r\n\r\nnext";
+        let mut reader = &chunked[..];
+        assert_eq!(
+            read_chunked_body(&mut reader).await.unwrap(),
+            b"hello world"
+        );
+        assert_eq!(reader, b"next");
+
+        // an endless chunk size line
+        let mut endless = BufReader::new(tokio::io::repeat(b'a'));
+        assert!(matches!(
+            read_chunked_body(&mut endless).await,
+            Err(ProxyError::InvalidHttpMessage(_))
+        ));
+        // a size which overflows once added up
+        let huge = b"1\r\na\r\nffffffffffffffff\r\n";
+        assert!(matches!(
+            read_chunked_body(&mut &huge[..]).await,
+            Err(ProxyError::InvalidHttpMessage(_))
+        ));
+        // endless trailers
+        let mut trailers = b"0\r\n".to_vec();
+        for _ in 0..MAX_HEAD_LEN / 4 {
+            trailers.extend_from_slice(b"A: b\r\n");
+        }
+        assert!(read_chunked_body(&mut &trailers[..]).await.is_err());
+    }
+}
@@ -1,12 +1,106 @@
-use std::{convert::TryInto, pin::Pin, task::Poll};
+pub mod h2;
+pub mod http1;
+pub mod polling;
+pub mod quic;
+pub mod tls;
+
+use std::{convert::TryInto, pin::Pin, str::FromStr, task::Poll};
 
 use futures::{Sink, Stream};
 use log::{debug, error, info};
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use url::Url;
+
+use crate::{
+    codec::Packet,
+    error::{ProxyError, ProxyResult},
+};
+
+/// Any byte stream a websocket can run on
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+/// Transport used to carry tunnel sessions between client and server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportType {
+    /// one pooled tls websocket connection per session
+    WebSocket,
+    /// pooled websockets as extended CONNECT streams of one http/2 connection
+    Http2,
+    /// one quic stream per session, udp associate over quic datagrams
+    Quic,
+}
 
-use crate::codec::Packet;
+impl FromStr for TransportType {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "websocket" | "ws" => Ok(TransportType::WebSocket),
+            "h2" | "http2" => Ok(TransportType::Http2),
+            "quic" => Ok(TransportType::Quic),
+            _ => Err("Could not parse a transport, expected websocket, h2 or quic"),
+        }
+    }
+}
+
+/// Where the client connects to and what it tells the server, each part can differ
+/// so the server may sit behind a shared reverse proxy or a cdn
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerEndpoint {
+    /// websocket url, its authority is sent as the `Host` header
+    pub url: Url,
+    /// tls server name
+    pub sni: String,
+    /// `host:port` the tcp connection is made to
+    pub connect_addr: String,
+    /// `Sec-WebSocket-Protocol` to offer, for servers requiring one
+    pub protocol: Option<String>,
+}
+
+impl ServerEndpoint {
+    /// `proxy_addr` is `host[:port]`, it is also the default host, sni and connect address
+    pub fn new(proxy_addr: &str, path: &str) -> ProxyResult<Self> {
+        if proxy_addr.is_empty() {
+            return Err(ProxyError::EmptyParams);
+        }
+        let mut url = Url::parse(&format!("wss://{}", proxy_addr))?;
+        url.set_path(path);
+        let host = url.host_str().ok_or(ProxyError::EmptyParams)?;
+        let sni = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let connect_addr = format!("{}:{}", host, url.port_or_known_default().unwrap_or(443));
+        Ok(Self {
+            url,
+            sni,
+            connect_addr,
+            protocol: None,
+        })
+    }
+
+    /// send `host` (`host[:port]`) as the `Host` header instead
+    pub fn set_host(&mut self, host: &str) -> ProxyResult<()> {
+        let mut url = Url::parse(&format!("wss://{}", host))?;
+        url.set_path(self.url.path());
+        self.url = url;
+        Ok(())
+    }
+
+    /// value of the `Host` header
+    pub fn host(&self) -> String {
+        let host = self.url.host_str().unwrap_or_default();
+        match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }
+    }
+}
 
 #[pin_project]
 pub struct WebSocketConnection<T>(#[pin] pub WebSocketStream<T>);
@@ -158,3 +252,21 @@ impl<T> WebSocketConnection<T> {
             })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_server_endpoint() {
+        let mut endpoint = ServerEndpoint::new("proxy.com", "/ws/tunnel").unwrap();
+        assert_eq!(endpoint.url.as_str(), "wss://proxy.com/ws/tunnel");
+        assert_eq!(endpoint.sni, "proxy.com");
+        assert_eq!(endpoint.connect_addr, "proxy.com:443");
+
+        endpoint.set_host("front.com:8443").unwrap();
+        assert_eq!(endpoint.url.as_str(), "wss://front.com:8443/ws/tunnel");
+        assert_eq!(endpoint.host(), "front.com:8443");
+        assert_eq!(endpoint.connect_addr, "proxy.com:443");
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use log::{error, info};
+use rand::Rng;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    transport::{http1::HttpConnection, BoxStream, ServerEndpoint},
+};
+
+/// last path segment of polling requests, `.../poll` opens a session and `.../poll/<id>` uses it
+pub const POLL_SEGMENT: &str = "poll";
+/// server holds a GET this long when there is nothing to send
+const POLL_TIMEOUT: Duration = Duration::from_secs(20);
+/// sessions without any request for this long are dropped
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_CHUNK_LEN: usize = 64 * 1024;
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+/// parse a polling request path into the websocket path it belongs to and the session id,
+/// no id means the request opens a session
+pub fn parse_path(path: &str) -> Option<(&str, Option<&str>)> {
+    let path = path.split('?').next().unwrap_or_default();
+    let (rest, last) = path.rsplit_once('/')?;
+    if last == POLL_SEGMENT {
+        return Some((rest, None));
+    }
+    let (prefix, parent) = rest.rsplit_once('/')?;
+    if parent == POLL_SEGMENT && !last.is_empty() {
+        return Some((prefix, Some(last)));
+    }
+    None
+}
+
+struct Session {
+    reader: tokio::sync::Mutex<ReadHalf<DuplexStream>>,
+    writer: tokio::sync::Mutex<WriteHalf<DuplexStream>>,
+    last_seen: Mutex<Instant>,
+}
+
+/// Server side polling sessions, each one is a byte pipe the websocket protocol runs on
+#[derive(Clone, Default)]
+pub struct PollingSessions(Arc<Mutex<HashMap<String, Arc<Session>>>>);
+
+impl PollingSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// create a session, returns its id and the server end of its pipe
+    pub fn open(&self) -> (String, DuplexStream) {
+        let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let (local, remote) = tokio::io::duplex(PIPE_CAPACITY);
+        let (reader, writer) = split(remote);
+        let session = Arc::new(Session {
+            reader: tokio::sync::Mutex::new(reader),
+            writer: tokio::sync::Mutex::new(writer),
+            last_seen: Mutex::new(Instant::now()),
+        });
+        self.0.lock().unwrap().insert(id.clone(), session);
+        tokio::spawn(self.clone().expire(id.clone()));
+        (id, local)
+    }
+
+    async fn expire(self, id: String) {
+        loop {
+            tokio::time::sleep(SESSION_IDLE_TIMEOUT / 2).await;
+            let mut sessions = self.0.lock().unwrap();
+            let last_seen = match sessions.get(&id) {
+                Some(session) => *session.last_seen.lock().unwrap(),
+                None => return,
+            };
+            if last_seen.elapsed() > SESSION_IDLE_TIMEOUT {
+                info!("polling session {} expired", id);
+                sessions.remove(&id);
+                return;
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Session>> {
+        let session = self.0.lock().unwrap().get(id).cloned();
+        if let Some(session) = &session {
+            *session.last_seen.lock().unwrap() = Instant::now();
+        }
+        session
+    }
+
+    fn close(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+
+    /// handle a request addressed to session `id`, returns the response status and body
+    pub async fn handle(&self, method: &str, id: &str, body: Vec<u8>) -> (StatusCode, Vec<u8>) {
+        let session = match self.get(id) {
+            Some(session) => session,
+            None => return (StatusCode::NOT_FOUND, Vec::new()),
+        };
+        match method {
+            // upstream bytes
+            "POST" => {
+                if session.writer.lock().await.write_all(&body).await.is_err() {
+                    self.close(id);
+                    return (StatusCode::GONE, Vec::new());
+                }
+                (StatusCode::OK, Vec::new())
+            }
+            // downstream bytes, waits until some are available
+            "GET" => {
+                let mut buf = vec![0u8; MAX_CHUNK_LEN];
+                let read = {
+                    let mut reader = session.reader.lock().await;
+                    tokio::time::timeout(POLL_TIMEOUT, reader.read(&mut buf)).await
+                };
+                let _ = self.get(id);
+                match read {
+                    Err(_) => (StatusCode::OK, Vec::new()),
+                    Ok(Ok(n)) if n > 0 => {
+                        buf.truncate(n);
+                        (StatusCode::OK, buf)
+                    }
+                    Ok(_) => {
+                        self.close(id);
+                        (StatusCode::GONE, Vec::new())
+                    }
+                }
+            }
+            "DELETE" => {
+                self.close(id);
+                (StatusCode::OK, Vec::new())
+            }
+            _ => (StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        }
+    }
+}
+
+/// Client side of the polling transport, used when websocket upgrades are stripped on the way
+#[derive(Clone)]
+pub struct PollingConnector {
+    tls_connector: TlsConnector,
+}
+
+impl PollingConnector {
+    /// `tls_connector` should offer `http/1.1` via alpn
+    pub fn new(tls_connector: TlsConnector) -> Self {
+        Self { tls_connector }
+    }
+
+    /// open a polling session, the returned stream carries websocket frames like a tls stream would
+    pub async fn open(
+        &self,
+        endpoint: &ServerEndpoint,
+        authorization: &str,
+    ) -> ProxyResult<BoxStream> {
+        let open_path = format!(
+            "{}/{}",
+            endpoint.url.path().trim_end_matches('/'),
+            POLL_SEGMENT
+        );
+
+        let mut upload = HttpConnection::new(self.tls_connector.clone(), endpoint.clone());
+        let mut headers = vec![("Authorization", authorization)];
+        if let Some(protocol) = &endpoint.protocol {
+            headers.push(("Sec-WebSocket-Protocol", protocol.as_str()));
+        }
+        let res = upload.request("POST", &open_path, &headers, &[]).await?;
+        if res.status != StatusCode::OK {
+            return Err(ProxyError::InvalidServerStatus {
+                expected: StatusCode::OK.to_string(),
+                found: res.status.to_string(),
+            });
+        }
+        let id = String::from_utf8(res.body)
+            .map_err(|_| ProxyError::InvalidHttpMessage("invalid session id".to_string()))?;
+        info!("polling session {} opened", id);
+
+        let path = format!("{}/{}", open_path, id);
+        let (local, remote) = tokio::io::duplex(PIPE_CAPACITY);
+        let (reader, writer) = split(remote);
+        let download = HttpConnection::new(self.tls_connector.clone(), endpoint.clone());
+        tokio::spawn(upload_loop(upload, path.clone(), reader));
+        tokio::spawn(download_loop(download, path, writer));
+        Ok(Box::new(local))
+    }
+}
+
+async fn upload_loop(mut conn: HttpConnection, path: String, mut reader: ReadHalf<DuplexStream>) {
+    let mut buf = vec![0u8; MAX_CHUNK_LEN];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(n) if n > 0 => n,
+            _ => break,
+        };
+        match conn.request("POST", &path, &[], &buf[..n]).await {
+            Ok(res) if res.status == StatusCode::OK => {}
+            Ok(res) => {
+                info!("polling upload stopped with status {}", res.status);
+                break;
+            }
+            Err(e) => {
+                error!("polling upload failed, detail is {:?}", e);
+                break;
+            }
+        }
+    }
+    let _ = conn.request("DELETE", &path, &[], &[]).await;
+}
+
+async fn download_loop(
+    mut conn: HttpConnection,
+    path: String,
+    mut writer: WriteHalf<DuplexStream>,
+) {
+    loop {
+        match conn.request("GET", &path, &[], &[]).await {
+            Ok(res) if res.status == StatusCode::OK => {
+                if writer.write_all(&res.body).await.is_err() {
+                    break;
+                }
+            }
+            Ok(res) => {
+                info!("polling download stopped with status {}", res.status);
+                break;
+            }
+            Err(e) => {
+                error!("polling download failed, detail is {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path("/poll"), Some(("", None)));
+        assert_eq!(parse_path("/tunnel/poll?x=1"), Some(("/tunnel", None)));
+        assert_eq!(parse_path("/poll/abc"), Some(("", Some("abc"))));
+        assert_eq!(parse_path("/"), None);
+        assert_eq!(parse_path("/poll/abc/def"), None);
+    }
+}
@@ -0,0 +1,650 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{error, info};
+use pin_project::pin_project;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self as quic_rustls,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            Resumption, WebPkiServerVerifier,
+        },
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+        server::{
+            NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+            WebPkiClientVerifier,
+        },
+        DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+
+use crate::{
+    codec::{Addr, Packet},
+    error::{ProxyError, ProxyResult},
+    transport::tls::{self, check_spki_pins, ClientTlsOptions, ServerTlsOptions},
+    util::load_native_certs,
+};
+
+/// ALPN id negotiated by both sides of the quic transport
+pub const ALPN_QUIC: &[u8] = b"ss-quic";
+/// max length of the hello sent on the first uni stream
+pub const MAX_HELLO_LEN: usize = 4096;
+
+const UDP_CHANNEL_SIZE: usize = 64;
+
+// quinn ships its own rustls, certs loaded by `util` are handed over as der
+fn crypto_provider() -> Arc<quic_rustls::crypto::CryptoProvider> {
+    Arc::new(quic_rustls::crypto::ring::default_provider())
+}
+
+// provider offering the suites named by `names` in their order, all of them when empty
+fn provider_with_cipher_suites(names: &[String]) -> Arc<quic_rustls::crypto::CryptoProvider> {
+    let mut provider = quic_rustls::crypto::ring::default_provider();
+    if !names.is_empty() {
+        // tls 1.2 names do not apply here
+        let position = |suite: &quic_rustls::SupportedCipherSuite| {
+            names
+                .iter()
+                .position(|name| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+        };
+        provider
+            .cipher_suites
+            .retain(|suite| position(suite).is_some());
+        provider.cipher_suites.sort_by_key(position);
+    }
+    Arc::new(provider)
+}
+
+/// convert certificates and key loaded by `util` for quinn's rustls
+pub fn into_der(
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+) -> ProxyResult<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = certs
+        .into_iter()
+        .map(|cert| CertificateDer::from(cert.0))
+        .collect();
+    let key = PrivateKeyDer::try_from(key.0).or(Err(ProxyError::InvalidPrivateKey))?;
+    Ok((certs, key))
+}
+
+/// build a quic server endpoint listening on `listen_addr`,
+/// `options` apply as far as tls 1.3 goes, alpn is always `ALPN_QUIC`
+pub fn make_server_endpoint(
+    listen_addr: SocketAddr,
+    cert_resolver: Arc<dyn ResolvesServerCert>,
+    options: &ServerTlsOptions,
+) -> ProxyResult<Endpoint> {
+    if !options.versions.is_empty() && !options.versions.contains(&rustls::ProtocolVersion::TLSv1_3)
+    {
+        return Err(ProxyError::UnsupportedTlsOption(
+            "quic needs tls 1.3".to_string(),
+        ));
+    }
+    let builder = quic_rustls::ServerConfig::builder_with_provider(provider_with_cipher_suites(
+        &options.cipher_suites,
+    ))
+    .with_protocol_versions(&[&quic_rustls::version::TLS13])?;
+    let builder = if options.client_cas.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for cert in &options.client_cas {
+            roots
+                .add(CertificateDer::from(cert.0.clone()))
+                .or(Err(ProxyError::InvalidCert))?;
+        }
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider())
+                .build()
+                .map_err(|e| ProxyError::Unknown(format!("{:?}", e)))?;
+        builder.with_client_cert_verifier(verifier)
+    };
+    let mut tls_config = builder.with_cert_resolver(cert_resolver);
+    tls_config.alpn_protocols = vec![ALPN_QUIC.to_vec()];
+    tls_config.ignore_client_order = options.prefer_server_ciphers;
+    if options.session_tickets {
+        tls_config.ticketer = quic_rustls::crypto::ring::Ticketer::new()?;
+    }
+    if options.session_cache_size == 0 {
+        tls_config.session_storage = Arc::new(NoServerSessionStorage {});
+        if !options.session_tickets {
+            tls_config.send_tls13_tickets = 0;
+        }
+    } else {
+        tls_config.session_storage = ServerSessionMemoryCache::new(options.session_cache_size);
+    }
+
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config)?));
+    server_config.transport_config(Arc::new(transport_config()));
+    Ok(Endpoint::server(server_config, listen_addr)?)
+}
+
+fn transport_config() -> TransportConfig {
+    let mut config = TransportConfig::default();
+    // every proxied connection is a stream, do not let the default limit throttle browsers
+    config.max_concurrent_bidi_streams(1024u32.into());
+    config.keep_alive_interval(Some(Duration::from_secs(10)));
+    config
+}
+
+/// One bidirectional quic stream, carries a single proxied connection
+#[pin_project]
+pub struct QuicStream {
+    #[pin]
+    send: SendStream,
+    #[pin]
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+
+    /// write length prefixed packet at the head of the stream
+    pub async fn write_packet(&mut self, packet: Packet) -> ProxyResult<()> {
+        let data = packet.encode()?;
+        self.send
+            .write_all(&(data.len() as u16).to_be_bytes())
+            .await?;
+        self.send.write_all(&data).await?;
+        Ok(())
+    }
+
+    /// read length prefixed packet from the head of the stream
+    pub async fn read_packet(&mut self) -> ProxyResult<Packet> {
+        let mut len = [0u8; 2];
+        self.recv.read_exact(&mut len).await?;
+        let mut data = vec![0u8; u16::from_be_bytes(len) as usize];
+        self.recv.read_exact(&mut data).await?;
+        Packet::decode(data)
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        AsyncRead::poll_read(self.project().recv, cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        AsyncWrite::poll_write(self.project().send, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        AsyncWrite::poll_flush(self.project().send, cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        AsyncWrite::poll_shutdown(self.project().send, cx)
+    }
+}
+
+/// datagram layout: association id, addr length, addr (`Addr::to_bytes`), payload,
+/// addrs longer than a length byte holds are rejected
+pub fn encode_datagram(id: u32, addr: &Addr, payload: &[u8]) -> ProxyResult<Bytes> {
+    let mut addr_bytes = BytesMut::new();
+    addr.to_bytes(&mut addr_bytes);
+    if addr_bytes.len() > u8::MAX as usize {
+        return Err(ProxyError::InvalidDatagram);
+    }
+    let mut datagram = BytesMut::with_capacity(5 + addr_bytes.len() + payload.len());
+    datagram.put_u32(id);
+    datagram.put_u8(addr_bytes.len() as u8);
+    datagram.put(addr_bytes);
+    datagram.put_slice(payload);
+    Ok(datagram.freeze())
+}
+
+pub fn decode_datagram(mut datagram: Bytes) -> ProxyResult<(u32, Addr, Bytes)> {
+    if datagram.len() < 5 {
+        return Err(ProxyError::InvalidDatagram);
+    }
+    let id = datagram.get_u32();
+    let addr_len = datagram.get_u8() as usize;
+    if datagram.len() < addr_len {
+        return Err(ProxyError::InvalidDatagram);
+    }
+    let addr = Addr::from_bytes(&datagram.split_to(addr_len))?;
+    Ok((id, addr, datagram))
+}
+
+/// First uni stream of a connection, the server routes it like a websocket upgrade
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuicHello {
+    pub path: String,
+    /// in place of `Sec-WebSocket-Protocol`
+    pub protocol: Option<String>,
+    pub authorization: Vec<u8>,
+}
+
+impl QuicHello {
+    /// path, protocol (empty when unset) and authorization, separated by newlines
+    pub fn encode(&self) -> Vec<u8> {
+        let mut hello = Vec::new();
+        hello.extend_from_slice(self.path.as_bytes());
+        hello.push(b'\n');
+        hello.extend_from_slice(self.protocol.as_deref().unwrap_or_default().as_bytes());
+        hello.push(b'\n');
+        hello.extend_from_slice(&self.authorization);
+        hello
+    }
+
+    pub fn decode(hello: &[u8]) -> ProxyResult<Self> {
+        let mut parts = hello.splitn(3, |b| *b == b'\n');
+        let mut next_str = || {
+            parts
+                .next()
+                .and_then(|part| std::str::from_utf8(part).ok())
+                .map(str::to_string)
+                .ok_or(ProxyError::InvalidQuicHello)
+        };
+        let path = next_str()?;
+        let protocol = Some(next_str()?).filter(|p| !p.is_empty());
+        let authorization = parts.next().ok_or(ProxyError::InvalidQuicHello)?;
+        Ok(Self {
+            path,
+            protocol,
+            authorization: authorization.to_vec(),
+        })
+    }
+}
+
+type UdpRoutes = Arc<Mutex<HashMap<u32, mpsc::Sender<(Addr, Bytes)>>>>;
+
+#[derive(Clone)]
+struct QuicSession {
+    // keep the endpoint alive as long as the connection
+    _endpoint: Endpoint,
+    connection: Connection,
+    udp_routes: UdpRoutes,
+}
+
+/// Client side of the quic transport, all proxied connections share one quic connection
+#[derive(Clone)]
+pub struct QuicConnector {
+    roots: RootCertStore,
+    spki_pins: Vec<[u8; 32]>,
+    danger_accept_invalid_certs: bool,
+    client_cert: Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>,
+    cipher_suites: Vec<String>,
+    resumption: Resumption,
+    enable_sni: bool,
+    server_addr: Arc<String>,
+    server_name: Arc<String>,
+    hello: Arc<QuicHello>,
+    session: Arc<tokio::sync::Mutex<Option<QuicSession>>>,
+    next_association: Arc<AtomicU32>,
+}
+
+impl QuicConnector {
+    /// `server_addr` is `host:port`, host is also used as sni
+    pub fn new(server_addr: String, authorization: String) -> Self {
+        let server_name = server_addr
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or(&server_addr)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(
+            load_native_certs()
+                .into_iter()
+                .map(|cert| CertificateDer::from(cert.0)),
+        );
+        Self {
+            roots,
+            spki_pins: Vec::new(),
+            danger_accept_invalid_certs: false,
+            client_cert: None,
+            cipher_suites: Vec::new(),
+            resumption: Resumption::default(),
+            enable_sni: true,
+            server_addr: Arc::new(server_addr),
+            server_name: Arc::new(server_name),
+            hello: Arc::new(QuicHello {
+                path: "/".to_string(),
+                protocol: None,
+                authorization: authorization.into_bytes(),
+            }),
+            session: Arc::new(tokio::sync::Mutex::new(None)),
+            next_association: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// use `server_name` as sni instead of the host of the server address
+    pub fn set_server_name(&mut self, server_name: String) {
+        self.server_name = Arc::new(server_name);
+    }
+
+    /// route on the server to ask for, as the path and subprotocol of a websocket url
+    pub fn set_route(&mut self, path: String, protocol: Option<String>) {
+        let hello = Arc::make_mut(&mut self.hello);
+        hello.path = path;
+        hello.protocol = protocol;
+        // clones keep the connection made for their own route
+        self.session = Arc::new(tokio::sync::Mutex::new(None));
+    }
+
+    /// trust an extra certificate authority besides the native roots
+    pub fn add_certificate_authority(&mut self, cert: rustls::Certificate) -> ProxyResult<()> {
+        self.roots
+            .add(CertificateDer::from(cert.0))
+            .or(Err(ProxyError::InvalidCert))
+    }
+
+    /// trust extra authorities, pin the server key or turn verification off,
+    /// ClientHello settings apply as far as tls 1.3 goes, alpn is always `ALPN_QUIC`
+    pub fn set_tls_options(&mut self, options: &ClientTlsOptions) -> ProxyResult<()> {
+        for cert in &options.ca_certs {
+            self.add_certificate_authority(cert.clone())?;
+        }
+        self.spki_pins = options.spki_pins.clone();
+        self.danger_accept_invalid_certs = options.danger_accept_invalid_certs;
+        self.client_cert = options.client_cert.clone();
+        self.cipher_suites = options.cipher_suites.clone();
+        // kept across connections so reconnects can resume
+        self.resumption = if options.session_cache_size == 0 {
+            Resumption::disabled()
+        } else {
+            Resumption::in_memory_sessions(options.session_cache_size)
+        };
+        self.enable_sni = options.enable_sni;
+        Ok(())
+    }
+
+    fn client_config(&self) -> ProxyResult<ClientConfig> {
+        let builder = quic_rustls::ClientConfig::builder_with_provider(
+            provider_with_cipher_suites(&self.cipher_suites),
+        )
+        .with_protocol_versions(&[&quic_rustls::version::TLS13])?;
+        let builder = if self.spki_pins.is_empty() && !self.danger_accept_invalid_certs {
+            builder.with_root_certificates(self.roots.clone())
+        } else {
+            let webpki = if self.danger_accept_invalid_certs {
+                None
+            } else {
+                Some(
+                    WebPkiServerVerifier::builder_with_provider(
+                        Arc::new(self.roots.clone()),
+                        crypto_provider(),
+                    )
+                    .build()
+                    .map_err(|e| ProxyError::Unknown(format!("{:?}", e)))?,
+                )
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    webpki,
+                    spki_pins: self.spki_pins.clone(),
+                    provider: crypto_provider(),
+                }))
+        };
+        let mut tls_config = match &self.client_cert {
+            Some((certs, key)) => {
+                let (certs, key) = into_der(certs.clone(), key.clone())?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        tls_config.alpn_protocols = vec![ALPN_QUIC.to_vec()];
+        tls_config.resumption = self.resumption.clone();
+        tls_config.enable_sni = self.enable_sni;
+        let mut client_config =
+            ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config)?));
+        client_config.transport_config(Arc::new(transport_config()));
+        Ok(client_config)
+    }
+
+    async fn session(&self) -> ProxyResult<QuicSession> {
+        let mut session = self.session.lock().await;
+        if let Some(s) = session.as_ref() {
+            if s.connection.close_reason().is_none() {
+                return Ok(s.clone());
+            }
+        }
+        let s = self.connect().await?;
+        *session = Some(s.clone());
+        Ok(s)
+    }
+
+    async fn connect(&self) -> ProxyResult<QuicSession> {
+        let addr = tokio::net::lookup_host(self.server_addr.as_str())
+            .await?
+            .next()
+            .ok_or_else(|| ProxyError::Unknown(format!("cannot resolve {}", self.server_addr)))?;
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let mut endpoint = Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(self.client_config()?);
+        let connection = endpoint.connect(addr, self.server_name.as_str())?.await?;
+        info!("quic connection to {} established", addr);
+
+        // first uni stream carries the route and the authorization
+        let mut hello = connection.open_uni().await?;
+        hello.write_all(&self.hello.encode()).await?;
+        hello.finish()?;
+
+        let session = QuicSession {
+            _endpoint: endpoint,
+            connection: connection.clone(),
+            udp_routes: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let udp_routes = session.udp_routes.clone();
+        tokio::spawn(async move {
+            loop {
+                let datagram = match connection.read_datagram().await {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        info!("quic connection closed, detail is {:?}", e);
+                        break;
+                    }
+                };
+                match decode_datagram(datagram) {
+                    Ok((id, addr, payload)) => {
+                        let route = udp_routes.lock().unwrap().get(&id).cloned();
+                        if let Some(route) = route {
+                            let _ = route.try_send((addr, payload));
+                        }
+                    }
+                    Err(e) => error!("invalid datagram from server, detail is {:?}", e),
+                }
+            }
+            udp_routes.lock().unwrap().clear();
+        });
+        Ok(session)
+    }
+
+    /// open a new stream and ask server to connect to `addr`
+    pub async fn open(&self, addr: Addr) -> ProxyResult<QuicStream> {
+        let session = self.session().await?;
+        let (send, recv) = match session.connection.open_bi().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // connection is broken, reconnect once
+                info!("open quic stream failed, reconnect; error={:?}", e);
+                self.session().await?.connection.open_bi().await?
+            }
+        };
+        let mut stream = QuicStream::new(send, recv);
+        stream.write_packet(Packet::Connect(addr)).await?;
+        Ok(stream)
+    }
+
+    /// register a new udp association, datagrams are relayed over the shared connection
+    pub async fn associate(&self) -> ProxyResult<UdpAssociation> {
+        let session = self.session().await?;
+        let id = self.next_association.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(UDP_CHANNEL_SIZE);
+        session.udp_routes.lock().unwrap().insert(id, tx);
+        Ok(UdpAssociation {
+            id,
+            connection: session.connection,
+            udp_routes: session.udp_routes,
+            rx,
+        })
+    }
+}
+
+/// identity of the client certificate of a quic connection, if it presented one
+pub fn peer_identity(connection: &Connection) -> Option<String> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    tls::peer_identity(certs.first()?)
+}
+
+/// Checks pins on top of webpki verification, which is skipped when `webpki` is unset
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    spki_pins: Vec<[u8; 32]>,
+    provider: Arc<quic_rustls::crypto::CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, quic_rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !check_spki_pins(&self.spki_pins, end_entity) {
+            return Err(quic_rustls::Error::General(
+                "server key does not match any pin".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, quic_rustls::Error> {
+        quic_rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, quic_rustls::Error> {
+        quic_rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Client side udp association, unregisters itself when dropped
+pub struct UdpAssociation {
+    id: u32,
+    connection: Connection,
+    udp_routes: UdpRoutes,
+    rx: mpsc::Receiver<(Addr, Bytes)>,
+}
+
+impl UdpAssociation {
+    pub fn send(&self, addr: &Addr, payload: &[u8]) -> ProxyResult<()> {
+        self.connection
+            .send_datagram(encode_datagram(self.id, addr, payload)?)?;
+        Ok(())
+    }
+
+    /// returns None once the quic connection is gone
+    pub async fn recv(&mut self) -> Option<(Addr, Bytes)> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        if let Ok(mut routes) = self.udp_routes.lock() {
+            routes.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datagram() {
+        let domain = |len: usize| Addr::Domain(("a".repeat(len), 53));
+        for addr in [Addr::IpV4(([10, 0, 0, 1], 53)), domain(252)] {
+            let datagram = encode_datagram(7, &addr, b"payload").unwrap();
+            let (id, decoded, payload) = decode_datagram(datagram).unwrap();
+            assert_eq!((id, decoded, &payload[..]), (7, addr, &b"payload"[..]));
+        }
+        // the addr length no longer fits in its byte
+        assert!(matches!(
+            encode_datagram(7, &domain(253), b"payload"),
+            Err(ProxyError::InvalidDatagram)
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+use std::{convert::TryInto, str::FromStr, sync::Arc};
+
+use rustls::{
+    Certificate, ClientSessionMemoryCache, NoClientSessionStorage, ProtocolVersion, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, SupportedCipherSuite, TLSError, WebPKIVerifier,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, webpki::DNSNameRef, TlsConnector};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    error::{ProxyError, ProxyResult},
+    transport::ServerEndpoint,
+    util::load_native_certs,
+};
+
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+pub const ALPN_H2: &[u8] = b"h2";
+
+/// How the client trusts the server certificate and shapes its ClientHello
+#[derive(Debug, Clone)]
+pub struct ClientTlsOptions {
+    /// certificate authorities trusted besides the native roots
+    pub ca_certs: Vec<Certificate>,
+    /// sha256 of the server SubjectPublicKeyInfo, one has to match when not empty
+    pub spki_pins: Vec<[u8; 32]>,
+    /// skip chain and name verification, only meant for local integration tests
+    pub danger_accept_invalid_certs: bool,
+    /// certificate chain and key presented to servers requiring client certificates
+    pub client_cert: Option<(Vec<Certificate>, rustls::PrivateKey)>,
+    /// alpn offered on tcp in this order, empty offers only what the connection speaks,
+    /// http/1.1 connections leave out h2 as the server would pick it
+    pub alpn: Vec<Vec<u8>>,
+    /// names like `TLS13_AES_128_GCM_SHA256` in the order offered, empty keeps the rustls order
+    pub cipher_suites: Vec<String>,
+    /// ask for tls 1.2 session tickets
+    pub session_tickets: bool,
+    /// sessions kept for resumption, 0 turns it off
+    pub session_cache_size: usize,
+    /// send the server name, the certificate is checked against it either way
+    pub enable_sni: bool,
+}
+
+impl Default for ClientTlsOptions {
+    fn default() -> Self {
+        Self {
+            ca_certs: Vec::new(),
+            spki_pins: Vec::new(),
+            danger_accept_invalid_certs: false,
+            client_cert: None,
+            alpn: Vec::new(),
+            cipher_suites: Vec::new(),
+            session_tickets: true,
+            session_cache_size: 32,
+            enable_sni: true,
+        }
+    }
+}
+
+/// ClientHello settings close to what mainstream browsers send,
+/// rustls has no grease or extension reordering so this only gets as far as alpn and cipher order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsPreset {
+    Chrome,
+    Firefox,
+    Safari,
+}
+
+impl TlsPreset {
+    fn cipher_suites(self) -> &'static [&'static str] {
+        match self {
+            TlsPreset::Chrome => &[
+                "TLS13_AES_128_GCM_SHA256",
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS13_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+            ],
+            TlsPreset::Firefox => &[
+                "TLS13_AES_128_GCM_SHA256",
+                "TLS13_CHACHA20_POLY1305_SHA256",
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+            ],
+            TlsPreset::Safari => &[
+                "TLS13_AES_128_GCM_SHA256",
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS13_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+            ],
+        }
+    }
+
+    /// overwrite the ClientHello settings of `options`, trust settings are kept
+    pub fn apply(self, options: &mut ClientTlsOptions) {
+        options.alpn = vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()];
+        options.cipher_suites = self.cipher_suites().iter().map(|s| s.to_string()).collect();
+        options.session_tickets = true;
+        options.session_cache_size = 32;
+        options.enable_sni = true;
+    }
+}
+
+impl FromStr for TlsPreset {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "chrome" => Ok(TlsPreset::Chrome),
+            "firefox" => Ok(TlsPreset::Firefox),
+            "safari" => Ok(TlsPreset::Safari),
+            _ => Err(ProxyError::UnsupportedTlsOption(format!(
+                "tls preset {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// How the server terminates tls, empty lists keep the rustls defaults
+#[derive(Debug, Clone)]
+pub struct ServerTlsOptions {
+    /// clients have to present a certificate signed by one of these when not empty
+    pub client_cas: Vec<Certificate>,
+    /// protocol versions accepted on tcp, quic always runs tls 1.3
+    pub versions: Vec<ProtocolVersion>,
+    /// names like `TLS13_AES_128_GCM_SHA256`, in order of preference
+    pub cipher_suites: Vec<String>,
+    /// pick the cipher suite by our order instead of the client's
+    pub prefer_server_ciphers: bool,
+    /// alpn offered on tcp, quic uses its own
+    pub alpn: Vec<Vec<u8>>,
+    /// resume sessions with stateless tickets
+    pub session_tickets: bool,
+    /// sessions kept for stateful resumption, 0 turns it off
+    pub session_cache_size: usize,
+}
+
+impl Default for ServerTlsOptions {
+    fn default() -> Self {
+        Self {
+            client_cas: Vec::new(),
+            versions: Vec::new(),
+            cipher_suites: Vec::new(),
+            prefer_server_ciphers: false,
+            alpn: vec![ALPN_H2.to_vec(), ALPN_HTTP1.to_vec()],
+            session_tickets: false,
+            session_cache_size: 256,
+        }
+    }
+}
+
+/// `1.2` or `1.3`
+pub fn parse_tls_version(version: &str) -> ProxyResult<ProtocolVersion> {
+    match version.trim_start_matches("TLSv").trim_start_matches("tls") {
+        "1.2" => Ok(ProtocolVersion::TLSv1_2),
+        "1.3" => Ok(ProtocolVersion::TLSv1_3),
+        _ => Err(ProxyError::UnsupportedTlsOption(format!(
+            "tls version {}",
+            version
+        ))),
+    }
+}
+
+/// rustls cipher suites named by `names` in their order, every supported one when empty
+pub fn cipher_suites(names: &[String]) -> ProxyResult<Vec<&'static SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(rustls::ALL_CIPHERSUITES.to_vec());
+    }
+    names
+        .iter()
+        .map(|name| {
+            rustls::ALL_CIPHERSUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| ProxyError::UnsupportedTlsOption(format!("cipher suite {}", name)))
+        })
+        .collect()
+}
+
+/// alpn offered by a connection speaking `alpn_protocols`, see `ClientTlsOptions::alpn`
+fn client_alpn(alpn_protocols: &[&[u8]], options: &ClientTlsOptions) -> ProxyResult<Vec<Vec<u8>>> {
+    if options.alpn.is_empty() {
+        return Ok(alpn_protocols.iter().map(|p| p.to_vec()).collect());
+    }
+    let offered: Vec<Vec<u8>> = options
+        .alpn
+        .iter()
+        .filter(|p| p.as_slice() != ALPN_H2 || alpn_protocols.contains(&ALPN_H2))
+        .cloned()
+        .collect();
+    for protocol in alpn_protocols {
+        if !offered.iter().any(|p| p.as_slice() == *protocol) {
+            return Err(ProxyError::UnsupportedTlsOption(format!(
+                "alpn without {}",
+                String::from_utf8_lossy(protocol)
+            )));
+        }
+    }
+    Ok(offered)
+}
+
+/// client tls config trusting the native roots and `options`, offering `alpn_protocols`
+pub fn client_config(
+    alpn_protocols: &[&[u8]],
+    options: &ClientTlsOptions,
+) -> ProxyResult<rustls::ClientConfig> {
+    let mut config = rustls::ClientConfig::new();
+    for cert in load_native_certs() {
+        // skip roots which webpki cannot parse, as the native loader of tungstenite does
+        let _ = config.root_store.add(&cert);
+    }
+    // unlike the native ones, a root the user asked for has to be usable
+    for cert in &options.ca_certs {
+        config
+            .root_store
+            .add(cert)
+            .or(Err(ProxyError::InvalidCert))?;
+    }
+    if !options.spki_pins.is_empty() || options.danger_accept_invalid_certs {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier {
+                webpki: WebPKIVerifier::new(),
+                spki_pins: options.spki_pins.clone(),
+                danger_accept_invalid_certs: options.danger_accept_invalid_certs,
+            }));
+    }
+    if let Some((certs, key)) = &options.client_cert {
+        config.set_single_client_cert(certs.clone(), key.clone())?;
+    }
+    config.alpn_protocols = client_alpn(alpn_protocols, options)?;
+    if !options.cipher_suites.is_empty() {
+        config.ciphersuites = cipher_suites(&options.cipher_suites)?;
+    }
+    config.enable_tickets = options.session_tickets;
+    if options.session_cache_size == 0 {
+        config.session_persistence = Arc::new(NoClientSessionStorage {});
+    } else {
+        config.session_persistence = ClientSessionMemoryCache::new(options.session_cache_size);
+    }
+    config.enable_sni = options.enable_sni;
+    Ok(config)
+}
+
+/// identity of a peer certificate, the first dns, email or uri san, else the subject common name
+pub fn peer_identity(cert: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => return Some(name.to_string()),
+                _ => {}
+            }
+        }
+    }
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+    common_name
+}
+
+/// sha256 of the SubjectPublicKeyInfo of a der certificate
+pub fn spki_sha256(cert: &[u8]) -> ProxyResult<[u8; 32]> {
+    let (_, cert) = X509Certificate::from_der(cert).or(Err(ProxyError::InvalidCert))?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.public_key().raw);
+    Ok(digest.as_ref().try_into().expect("sha256 is 32 bytes"))
+}
+
+/// parse a pin given as base64, optionally prefixed with `sha256//` as curl does
+pub fn parse_spki_pin(pin: &str) -> ProxyResult<[u8; 32]> {
+    let pin = pin.trim_start_matches("sha256//");
+    base64::decode(pin)
+        .ok()
+        .and_then(|pin| pin.try_into().ok())
+        .ok_or_else(|| ProxyError::Unknown(format!("invalid spki pin {}", pin)))
+}
+
+/// whether the end entity certificate matches one of `spki_pins`, or there are none
+pub fn check_spki_pins(spki_pins: &[[u8; 32]], cert: &[u8]) -> bool {
+    if spki_pins.is_empty() {
+        return true;
+    }
+    match spki_sha256(cert) {
+        Ok(hash) => spki_pins.contains(&hash),
+        Err(_) => false,
+    }
+}
+
+/// Checks pins on top of the usual webpki verification, which can be turned off
+struct PinnedVerifier {
+    webpki: WebPKIVerifier,
+    spki_pins: Vec<[u8; 32]>,
+    danger_accept_invalid_certs: bool,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        if !self.danger_accept_invalid_certs {
+            self.webpki
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        }
+        let end_entity = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        if !check_spki_pins(&self.spki_pins, &end_entity.0) {
+            return Err(TLSError::General(
+                "server key does not match any pin".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+pub fn connector(config: rustls::ClientConfig) -> TlsConnector {
+    TlsConnector::from(Arc::new(config))
+}
+
+/// open a tls connection to the connect address of `endpoint`, using its sni
+pub async fn connect(
+    connector: &TlsConnector,
+    endpoint: &ServerEndpoint,
+) -> ProxyResult<TlsStream<TcpStream>> {
+    let tcp = TcpStream::connect(endpoint.connect_addr.as_str()).await?;
+    let domain = DNSNameRef::try_from_ascii_str(&endpoint.sni)
+        .map_err(|_| ProxyError::Unknown(format!("invalid dns name {}", endpoint.sni)))?;
+    Ok(connector.connect(domain, tcp).await?)
+}
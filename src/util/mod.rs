@@ -1,14 +1,24 @@
 use std::{
+    convert::TryFrom,
     fs::{File},
     io::{BufReader},
+    net::IpAddr,
     path::PathBuf,
 };
 
 
+use pkcs8::{
+    der::Encode, AlgorithmIdentifierRef, EncryptedPrivateKeyInfo, ObjectIdentifier, PrivateKeyInfo,
+};
 use rustls::internal::pemfile::certs;
 
 use crate::error::{ProxyError, ProxyResult};
 
+/// passphrase of encrypted private keys when no passphrase file is given
+pub const KEY_PASSPHRASE_ENV: &str = "SS_KEY_PASSPHRASE";
+/// `id-ecPublicKey`, algorithm of ec keys wrapped in pkcs#8
+const EC_PUBLIC_KEY_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+
 pub fn load_certs(filename: PathBuf) -> ProxyResult<Vec<rustls::Certificate>> {
     let certs = certs(&mut BufReader::new(File::open(filename)?));
     if let Ok(certs) = certs {
@@ -17,16 +27,239 @@ pub fn load_certs(filename: PathBuf) -> ProxyResult<Vec<rustls::Certificate>> {
     Err(ProxyError::InvalidCert)
 }
 
-pub fn load_private_key(filename: PathBuf) -> ProxyResult<rustls::PrivateKey> {
-    let mut reader = &mut BufReader::new(File::open(filename)?);
+/// passphrase from `filename` without the trailing newline, else from `SS_KEY_PASSPHRASE`
+pub fn load_key_passphrase(filename: Option<PathBuf>) -> ProxyResult<Option<String>> {
+    if let Some(filename) = filename {
+        let passphrase = std::fs::read_to_string(filename)?;
+        return Ok(Some(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string()));
+    }
+    Ok(std::env::var(KEY_PASSPHRASE_ENV).ok())
+}
+
+/// private key in pkcs#1 (rsa), sec1 (ec), pkcs#8 (rsa, ec, ed25519) or encrypted pkcs#8 form,
+/// pem or der encoded, sec1 and encrypted keys come back as plain pkcs#8
+pub fn load_private_key(
+    filename: PathBuf,
+    passphrase: Option<&str>,
+) -> ProxyResult<rustls::PrivateKey> {
+    let data = std::fs::read(filename)?;
+    let items = parse_pem(&data)?;
+    if items.is_empty() && data.first() == Some(&0x30) {
+        return der_private_key(&data, passphrase).map(rustls::PrivateKey);
+    }
 
-    loop {
-        match rustls_pemfile::read_one(&mut reader)? {
-            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(rustls::PrivateKey(key)),
-            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(rustls::PrivateKey(key)),
-            None => break,
-            _ => {}
+    for (label, der) in &items {
+        let key = match label.as_str() {
+            "RSA PRIVATE KEY" | "PRIVATE KEY" => der.clone(),
+            "EC PRIVATE KEY" => sec1_to_pkcs8(der)?,
+            "ENCRYPTED PRIVATE KEY" => decrypt_pkcs8(der, passphrase)?,
+            _ => continue,
+        };
+        return Ok(rustls::PrivateKey(key));
+    }
+    Err(ProxyError::NoPrivateKey(
+        items.into_iter().map(|(label, _)| label).collect(),
+    ))
+}
+
+/// guess the form of a der key, the outer sequences differ
+fn der_private_key(der: &[u8], passphrase: Option<&str>) -> ProxyResult<Vec<u8>> {
+    if EncryptedPrivateKeyInfo::try_from(der).is_ok() {
+        return decrypt_pkcs8(der, passphrase);
+    }
+    if sec1::EcPrivateKey::try_from(der).is_ok() {
+        return sec1_to_pkcs8(der);
+    }
+    Ok(der.to_vec())
+}
+
+/// label and content of every `-----BEGIN <label>-----` section,
+/// legacy openssl encryption headers are reported in the label since they cannot be read
+fn parse_pem(data: &[u8]) -> ProxyResult<Vec<(String, Vec<u8>)>> {
+    let text = String::from_utf8_lossy(data);
+    let mut items = Vec::new();
+    let mut current: Option<(String, String, bool)> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(label) = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|l| l.strip_suffix("-----"))
+        {
+            current = Some((label.to_string(), String::new(), false));
+            continue;
+        }
+        let (label, body, legacy) = match current.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+        if line.starts_with("-----END ") {
+            let der = base64::decode(&body).or(Err(ProxyError::InvalidPrivateKey))?;
+            let label = if *legacy {
+                format!("{} (legacy encryption)", label)
+            } else {
+                label.clone()
+            };
+            items.push((label, der));
+            current = None;
+        } else if line.contains(':') {
+            *legacy |= line.starts_with("Proc-Type") && line.contains("ENCRYPTED");
+        } else {
+            body.push_str(line);
         }
     }
-    Err(ProxyError::InvalidPrivateKey)
-}
\ No newline at end of file
+    Ok(items)
+}
+
+/// wrap a sec1 `ECPrivateKey` in pkcs#8, the only ec form rustls takes
+fn sec1_to_pkcs8(der: &[u8]) -> ProxyResult<Vec<u8>> {
+    let key = sec1::EcPrivateKey::try_from(der).or(Err(ProxyError::InvalidPrivateKey))?;
+    let curve = key
+        .parameters
+        .and_then(|p| p.named_curve())
+        .ok_or(ProxyError::InvalidPrivateKey)?;
+    let algorithm = AlgorithmIdentifierRef {
+        oid: EC_PUBLIC_KEY_OID,
+        parameters: Some((&curve).into()),
+    };
+    let key = PrivateKeyInfo::new(algorithm, der)
+        .to_der()
+        .map_err(pkcs8::Error::from)?;
+    Ok(key)
+}
+
+fn decrypt_pkcs8(der: &[u8], passphrase: Option<&str>) -> ProxyResult<Vec<u8>> {
+    let passphrase = passphrase.ok_or(ProxyError::KeyPassphraseRequired)?;
+    let key = EncryptedPrivateKeyInfo::try_from(der)?;
+    Ok(key.decrypt(passphrase)?.as_bytes().to_vec())
+}
+
+/// self signed certificate for `sans` (dns names or ip addresses) and its pkcs#8 key, both pem
+pub fn generate_self_signed(sans: &[String]) -> ProxyResult<(String, String)> {
+    let common_name = sans.first().ok_or(ProxyError::EmptyParams)?;
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+    params.subject_alt_names = sans
+        .iter()
+        .map(|san| match san.parse::<IpAddr>() {
+            Ok(ip) => rcgen::SanType::IpAddress(ip),
+            Err(_) => rcgen::SanType::DnsName(san.clone()),
+        })
+        .collect();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, common_name.as_str());
+    let cert = rcgen::Certificate::from_params(params)?;
+    Ok((cert.serialize_pem()?, cert.serialize_private_key_pem()))
+}
+
+/// random authorization secret, 32 bytes base64url encoded
+pub fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// root certificates trusted by the operating system
+pub fn load_native_certs() -> Vec<rustls::Certificate> {
+    rustls_native_certs::load_native_certs()
+        .certs
+        .into_iter()
+        .map(|cert| rustls::Certificate(cert.to_vec()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        format!(
+            "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+            label,
+            base64::encode(der),
+            label
+        )
+    }
+
+    #[test]
+    fn test_load_private_key() {
+        let dir = std::env::temp_dir().join(format!("ss-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |name: &str, data: &[u8], passphrase: Option<&str>| {
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            load_private_key(path, passphrase)
+        };
+        let usable = |key: &rustls::PrivateKey| rustls::sign::any_supported_type(key).is_ok();
+
+        let ec = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let ec_pkcs8 = ec.serialize_der();
+        // ring leaves the curve out of the inner key, openssl's sec1 files carry it
+        let info = PrivateKeyInfo::try_from(ec_pkcs8.as_slice()).unwrap();
+        let inner = sec1::EcPrivateKey::try_from(info.private_key).unwrap();
+        let ec_sec1 = sec1::EcPrivateKey {
+            parameters: Some(sec1::EcParameters::NamedCurve(
+                info.algorithm.parameters_oid().unwrap(),
+            )),
+            ..inner
+        }
+        .to_der()
+        .unwrap();
+        let ed25519 = rcgen::KeyPair::generate(&rcgen::PKCS_ED25519).unwrap();
+
+        let key = load("ec.pem", pem("EC PRIVATE KEY", &ec_sec1).as_bytes(), None).unwrap();
+        assert!(usable(&key));
+        assert!(usable(&load("ec.der", &ec_sec1, None).unwrap()));
+        assert!(usable(&load("ed25519.pem", ed25519.serialize_pem().as_bytes(), None).unwrap()));
+
+        // certificates before the key are skipped
+        let chain = pem("CERTIFICATE", b"cert") + &ec.serialize_pem();
+        assert!(usable(&load("chain.pem", chain.as_bytes(), None).unwrap()));
+
+        let params =
+            pkcs8::pkcs5::pbes2::Parameters::pbkdf2_sha256_aes256cbc(2048, b"saltsalt", &[7; 16])
+                .unwrap();
+        let encrypted = PrivateKeyInfo::try_from(ec_pkcs8.as_slice())
+            .unwrap()
+            .encrypt_with_params(params, "secret")
+            .unwrap();
+        let encrypted_pem = pem("ENCRYPTED PRIVATE KEY", encrypted.as_bytes());
+        let key = load("enc.pem", encrypted_pem.as_bytes(), Some("secret")).unwrap();
+        assert_eq!(key.0, ec_pkcs8);
+        assert!(matches!(
+            load("enc.pem", encrypted_pem.as_bytes(), None),
+            Err(ProxyError::KeyPassphraseRequired)
+        ));
+        assert!(load("enc.pem", encrypted_pem.as_bytes(), Some("wrong")).is_err());
+        assert!(usable(&load("enc.der", encrypted.as_bytes(), Some("secret")).unwrap()));
+
+        match load("cert.pem", pem("CERTIFICATE", b"cert").as_bytes(), None) {
+            Err(ProxyError::NoPrivateKey(items)) => assert_eq!(items, vec!["CERTIFICATE"]),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_generate_self_signed() {
+        let dir = std::env::temp_dir().join(format!("ss-gen-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sans = vec!["example.com".to_string(), "127.0.0.1".to_string()];
+        let (cert, key) = generate_self_signed(&sans).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+
+        let certs = load_certs(dir.join("cert.pem")).unwrap();
+        assert_eq!(certs.len(), 1);
+        let key = load_private_key(dir.join("key.pem"), None).unwrap();
+        assert!(rustls::sign::any_supported_type(&key).is_ok());
+        assert!(tokio_rustls::webpki::EndEntityCert::from(&certs[0].0)
+            .unwrap()
+            .verify_is_valid_for_dns_name(
+                tokio_rustls::webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap()
+            )
+            .is_ok());
+        assert!(generate_self_signed(&[]).is_err());
+        assert_ne!(generate_token(), generate_token());
+        assert_eq!(generate_token().len(), 43);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}